@@ -0,0 +1,36 @@
+//! Validates a config file without running anything and exits non-zero on any problem.
+//!
+//! `cargo run --example config_check -- examples/my_config.toml`
+
+use clams::config::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::process;
+
+#[derive(Config, Debug, Default, Serialize, Deserialize)]
+struct MyConfig {
+    general: General,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct General {
+    name: String,
+}
+
+fn main() {
+    let file_path = match env::args().nth(1) {
+        Some(file_path) => file_path,
+        None => {
+            eprintln!("usage: config_check <config file>");
+            process::exit(2);
+        }
+    };
+
+    match MyConfig::check_file(&file_path) {
+        Ok(()) => println!("{}: ok", file_path),
+        Err(e) => {
+            eprintln!("{}: {}", file_path, e);
+            process::exit(1);
+        }
+    }
+}
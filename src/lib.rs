@@ -1,24 +1,58 @@
+// error_chain! expands recursively per error kind; the config module has outgrown the default limit.
+#![recursion_limit = "256"]
+
 mod reexports {
     #[doc(hidden)] pub use colored::*;
     #[doc(hidden)] pub use indicatif::*;
     #[doc(hidden)] pub use log::*;
 }
 
+#[cfg(test)]
+mod test_util {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Mutex;
+
+    /// Serializes tests that toggle the process-wide `console::set_interactive` switch.
+    pub static INTERACTIVE_LOCK: Mutex<()> = Mutex::new(());
+
+    pub fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("clams-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Could not create test dir");
+        dir
+    }
+}
+
 pub mod prelude {
     pub use crate::reexports::*;
 
     pub use crate::config::{Config, default_locations};
-    pub use crate::console::ask_for_confirmation;
+    pub use crate::console::{ask_for_confirmation, ask_for_confirmation_any};
     pub use crate::fs::FileExt;
-    pub use crate::logging::{Level, LogConfig, ModLevel, init_logging};
+    pub use crate::logging::{Level, LogConfig, ModLevel, Verbosity, init_logging, install_panic_hook};
     pub use crate::progress::ProgressStyleExt;
 }
 
 pub mod config {
+    use crate::fs::{ensure_parent_dir, list_files, write_atomic};
+    #[cfg(not(windows))]
     use crate::fs::home_dir;
 
+    use chrono::Utc;
     use error_chain::*;
+    use serde::Serialize;
+    use serde::de::{self, DeserializeOwned};
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::env;
+    use std::fs;
+    use std::io::{self, Read, Write};
     use std::path::{Path, PathBuf};
+    use std::process;
+    use std::sync::RwLock;
+    use toml::Value;
 
     pub mod prelude {
         pub use crate::config::{Config, ConfigError, ConfigErrorKind, ConfigResult, ConfigResultExt};
@@ -34,419 +68,6422 @@ pub mod config {
         fn smart_load<T: AsRef<Path>>(file_paths: &[T]) -> ConfigResult<(Self::ConfigStruct, &Path)>;
 
         fn save<T: AsRef<Path>>(&self, file_path: T) -> ConfigResult<()>;
-    }
 
-    pub fn default_locations(config_file_name: &str) -> Vec<PathBuf> {
-        let mut locations: Vec<PathBuf> = Vec::new();
+        /// Like `smart_load` but fails with `ConfigErrorKind::AmbiguousConfig` listing all matches if more than one candidate exists.
+        fn smart_load_strict<T: AsRef<Path>>(file_paths: &[T]) -> ConfigResult<(Self::ConfigStruct, &Path)> {
+            let existing: Vec<&Path> = file_paths.iter().map(AsRef::as_ref).filter(|path| path.exists()).collect();
+            match existing.as_slice() {
+                [path] => Ok((Self::from_file(path)?, *path)),
+                [] => {
+                    let candidates = file_paths.iter().map(|path| path.as_ref().to_string_lossy().to_string()).collect();
+                    Err(no_suitable_config(candidates))
+                }
+                _ => {
+                    let matches = existing.iter().map(|path| path.to_string_lossy().to_string()).collect();
+                    Err(ConfigErrorKind::AmbiguousConfig(matches).into())
+                }
+            }
+        }
 
-        if let Some(mut path) = home_dir() {
-            let home_config = format!(".{}", config_file_name);
-            path.push(home_config);
-            locations.push(path);
+        /// Like `smart_load` but detects each candidate's format by extension, falling back to TOML, so
+        /// candidate lists may mix formats.
+        fn smart_load_detect<T: AsRef<Path>>(file_paths: &[T]) -> ConfigResult<(Self::ConfigStruct, &Path)> where Self::ConfigStruct: DeserializeOwned {
+            for file_path in file_paths {
+                if let Ok((config, _)) = Self::from_file_detect(file_path) {
+                    return Ok((config, file_path.as_ref()));
+                }
+            }
+
+            let candidates = file_paths.iter().map(|path| path.as_ref().to_string_lossy().to_string()).collect();
+            Err(no_suitable_config(candidates))
         }
 
-        let mut etc = PathBuf::new();
-        etc.push("/etc");
-        etc.push(config_file_name);
-        locations.push(etc);
+        fn from_file_detect<T: AsRef<Path>>(file_path: T) -> ConfigResult<(Self::ConfigStruct, Format)> where Self::ConfigStruct: DeserializeOwned {
+            let format = Format::from_path(&file_path);
+            let content = fs::read_to_string(file_path)?;
+            let config = format.parse(&content)?;
 
-        locations
-    }
+            Ok((config, format))
+        }
 
-    error_chain! {
-        types {
-            ConfigError, ConfigErrorKind, ConfigResultExt, ConfigResult;
+        fn from_yaml_file<T: AsRef<Path>>(file_path: T) -> ConfigResult<Self::ConfigStruct> where Self::ConfigStruct: DeserializeOwned {
+            let content = fs::read_to_string(file_path)?;
+
+            Format::Yaml.parse(&content)
         }
 
-        errors {
-            NoSuitableConfigFound(configs: Vec<String>) {
-                description("No suitable configuration found")
-                display("No suitable configuration found '{:?}'", configs)
+        fn from_reader<R: Read>(reader: R, format: Format) -> ConfigResult<Self::ConfigStruct> where Self::ConfigStruct: DeserializeOwned {
+            Self::from_reader_limited(reader, format, usize::MAX)
+        }
+
+        /// Parses the content of environment variable `var`. Content prefixed with `base64:` is decoded first; without
+        /// prefix, content that does not parse but decodes as base64 to UTF-8 is parsed decoded.
+        fn from_env_var(var: &str, format: Format) -> ConfigResult<Self::ConfigStruct> where Self::ConfigStruct: DeserializeOwned {
+            let content = match env::var(var) {
+                Ok(content) => content,
+                Err(env::VarError::NotPresent) => return Err(ConfigErrorKind::EnvVarNotSet(var.to_owned()).into()),
+                Err(e) => return Err(ConfigErrorKind::InvalidEnvVar(var.to_owned(), e.to_string()).into()),
+            };
+
+            if let Some(encoded) = content.trim().strip_prefix("base64:") {
+                let decoded = decode_base64(encoded)
+                    .map_err(|reason| ConfigErrorKind::InvalidEnvVar(var.to_owned(), reason))?;
+                return format.parse(&decoded);
             }
+            format.parse(&content).or_else(|e| match decode_base64(content.trim()) {
+                Ok(decoded) => format.parse(&decoded),
+                Err(_) => Err(e),
+            })
         }
 
-        foreign_links {
-            CouldNotRead(::std::io::Error);
-            CouldNotParse(::toml::de::Error);
-            CouldNotWrite(::toml::ser::Error);
+        /// Like `from_reader` but fails with `ConfigErrorKind::TooLarge` instead of reading more than `max_bytes`.
+        fn from_reader_limited<R: Read>(reader: R, format: Format, max_bytes: usize) -> ConfigResult<Self::ConfigStruct> where Self::ConfigStruct: DeserializeOwned {
+            let content = read_limited(reader, max_bytes)?;
+
+            format.parse(&content)
         }
-    }
 
-    #[cfg(test)]
-    mod test {
-        use super::*;
-        use clams_derive::Config;
-        use serde::{Deserialize, Serialize};
-        use spectral::prelude::*;
+        /// Fetches and parses a config via HTTP(S); a response that parses is written to `cache`, converted to the
+        /// format of the cache's extension, and the cached copy is used when the server cannot be reached.
+        #[cfg(feature = "remote")]
+        fn from_url<T: AsRef<Path>>(url: &str, cache: Option<T>) -> ConfigResult<Self::ConfigStruct> where Self::ConfigStruct: DeserializeOwned {
+            let (content, format) = match remote::fetch(url) {
+                Ok(fetched) => fetched,
+                Err(e) => match cache {
+                    Some(ref cache) if cache.as_ref().exists() => {
+                        log::warn!("Using cached config '{}' because fetching '{}' failed: {}", cache.as_ref().display(), url, e);
+                        return Format::from_path(cache).parse(&fs::read_to_string(cache)?);
+                    }
+                    _ => return Err(e),
+                },
+            };
 
-        #[derive(Config, Debug, Default, Serialize, Deserialize, PartialEq)]
-        struct MyConfig {
-            pub general: General,
+            let config = format.parse(&content)?;
+            if let Some(ref cache) = cache {
+                remote::write_cache(cache.as_ref(), &content, format)?;
+            }
+
+            Ok(config)
         }
 
-        #[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
-        struct General {
-            pub name: String,
+        /// Decrypts an age-encrypted file with the identities from `key_source` and parses it;
+        /// the format is detected from the path without a trailing `.age`, e.g. `app.toml.age`.
+        #[cfg(feature = "encryption")]
+        fn from_file_encrypted<T: AsRef<Path>>(file_path: T, key_source: &KeySource) -> ConfigResult<Self::ConfigStruct> where Self::ConfigStruct: DeserializeOwned {
+            let file_path = file_path.as_ref();
+            let content = encryption::decrypt(&fs::read(file_path)?, key_source)?;
+
+            encryption::plain_format(file_path).parse(&content)
         }
 
-        #[test]
-        fn from_file_okay() {
-            let my_config = MyConfig::from_file("examples/my_config.toml");
+        /// Serializes like `save` and encrypts the result to the recipients of the identities from `key_source`.
+        #[cfg(feature = "encryption")]
+        fn save_encrypted<T: AsRef<Path>>(&self, file_path: T, key_source: &KeySource) -> ConfigResult<()> where Self: Serialize {
+            let file_path = file_path.as_ref();
+            let content = encryption::plain_format(file_path).serialize(self)?;
+            let encrypted = encryption::encrypt(content.as_bytes(), key_source)?;
+            ensure_parent_dir(file_path)?;
+            fs::write(file_path, encrypted)?;
 
-            assert_that(&my_config).is_ok();
+            Ok(())
         }
 
-        #[test]
-        fn smart_load_okay() {
-            let locations = vec!["tmp/my_config.toml", "tmp2/my_config.toml", "examples/my_config.toml"];
+        /// Copies an existing `file_path` to a backup according to `backup`, pruning surplus timestamped backups, and
+        /// then atomically replaces it with `self` in the format of its extension. Returns the backup's path, if any.
+        /// Unlike `save`, `#[serde(flatten)]` fields are supported.
+        fn save_with_backup<T: AsRef<Path>>(&self, file_path: T, backup: Backup) -> ConfigResult<Option<PathBuf>> where Self: Serialize {
+            backup.validate()?;
+            let file_path = file_path.as_ref();
+            let content = Format::from_path(file_path).serialize(self)?;
+            let backup_path = if file_path.exists() {
+                let backup_path = backup.path_for(file_path);
+                fs::copy(file_path, &backup_path)?;
+                backup.prune(file_path)?;
+                Some(backup_path)
+            } else {
+                None
+            };
+            write_atomic(file_path, content)?;
 
-            let res = MyConfig::smart_load(&locations);
+            Ok(backup_path)
+        }
 
-            assert_that(&res).is_ok();
+        /// Serializes to `writer`, e.g. stdout or a network stream, without going through a file.
+        fn save_to_writer<W: Write>(&self, mut writer: W, format: Format) -> ConfigResult<()> where Self: Serialize {
+            let content = format.serialize(self)?;
+            writer.write_all(content.as_bytes())?;
+            writer.flush()?;
+
+            Ok(())
         }
 
-        #[test]
-        fn smart_load_faild() {
-            let locations = vec!["tmp/my_config.toml", "tmp2/my_config.toml"];
+        fn default_config_string(banner: Option<&str>) -> ConfigResult<String> where Self: Default + Serialize {
+            let mut content = String::new();
+            if let Some(banner) = banner {
+                for line in banner.lines() {
+                    if line.is_empty() {
+                        content.push_str("#\n");
+                    } else {
+                        content.push_str(&format!("# {}\n", line));
+                    }
+                }
+                content.push('\n');
+            }
+            content.push_str(&to_toml_string(&Self::default())?);
 
-            let res = MyConfig::smart_load(&locations);
+            Ok(content)
+        }
 
-            assert_that(&res).is_err();
+        fn write_default<T: AsRef<Path>>(file_path: T, banner: Option<&str>) -> ConfigResult<()> where Self: Default + Serialize {
+            let content = Self::default_config_string(banner)?;
+            ensure_parent_dir(&file_path)?;
+            fs::write(file_path, content)?;
+
+            Ok(())
         }
 
-        #[test]
-        fn default_locations_okay() {
-            let home_dir = home_dir().expect("Could not retrieve username");
-            let mut home_config = PathBuf::from(home_dir);
-            home_config.push(".my_config.toml");
-            let expected: Vec<PathBuf> = vec![
-                home_config,
-                PathBuf::from("/etc/my_config.toml"),
-            ];
+        /// Loads `file_path` or, if it does not exist yet, atomically writes the defaults there in the format of its
+        /// extension and returns them; an existing but invalid file is reported and never overwritten.
+        fn load_or_create<T: AsRef<Path>>(file_path: T) -> ConfigResult<Self> where Self: Sized + Default + Serialize + DeserializeOwned {
+            let file_path = file_path.as_ref();
+            if file_path.exists() {
+                return Ok(read_value(file_path)?.try_into()?);
+            }
 
-            let res = default_locations("my_config.toml");
+            write_atomic(file_path, Format::from_path(file_path).serialize(&Self::default())?)?;
+            log::info!("Created default configuration '{}'", file_path.display());
 
-            assert_that(&res).is_equal_to(expected);
+            Ok(Self::default())
         }
 
-        #[test]
-        fn smart_load_from_default_locations_and_local() {
-            let mut locations = default_locations("my_config.toml");
-            locations.push(PathBuf::from("examples/my_config.toml"));
+        /// Lists dotted paths and values of fields set to something other than `Self::default()`;
+        /// fields unset in `self` but set by default are omitted since TOML cannot express them.
+        fn non_default_fields(&self) -> ConfigResult<Vec<(String, String)>> where Self: Default + Serialize {
+            let defaults = Value::try_from(Self::default())?;
+            let current = Value::try_from(self)?;
+            let mut changes = Vec::new();
+            diff_values("", Some(&defaults), Some(&current), &mut changes);
 
-            let res = MyConfig::smart_load(&locations);
+            let fields = changes.into_iter()
+                .filter_map(|(path, _, new)| new.map(|value| (path, value.to_string())))
+                .collect();
 
-            assert_that(&res).is_ok();
+            Ok(fields)
         }
-    }
-}
 
-pub mod console {
-    use colored;
-    use std::io::{self, BufRead, BufReader, Write};
-    use error_chain::*;
+        /// Fully parses `file_path` and fails on parse errors, type mismatches and keys that are not part of the config.
+        fn check_file<T: AsRef<Path>>(file_path: T) -> ConfigResult<()> where Self: Sized + Serialize + DeserializeOwned {
+            let raw = read_value(file_path)?;
+            let config: Self = raw.clone().try_into()?;
+            let known = Value::try_from(&config)?;
+            let mut unknown = Vec::new();
+            collect_unknown_keys("", &raw, &known, &mut unknown);
 
-    pub fn ask_for_confirmation(prompt: &str, expected: &str) -> Result<bool> {
-        let mut reader = BufReader::new(io::stdin());
-        let mut writer = io::stdout();
-        ask_for_confirmation_from(&mut reader, &mut writer, prompt, expected)
-    }
+            if unknown.is_empty() {
+                Ok(())
+            } else {
+                Err(ConfigErrorKind::UnknownFields(unknown).into())
+            }
+        }
 
-    pub fn ask_for_confirmation_from<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, prompt: &str, expected: &str) -> Result<bool> {
-        let question = format!("{}", prompt);
-        writer.write(question.as_bytes())
-            .chain_err(|| ErrorKind::FailedToReadConfirmation)?;
-        writer.flush()
-            .chain_err(|| ErrorKind::FailedToReadConfirmation)?;
+        /// Serializes only fields that differ from `Self::default()`, omitting sections that are entirely default;
+        /// use `load_over` with `Self::default()` to load the result.
+        fn to_minimal_string(&self) -> ConfigResult<String> where Self: Default + Serialize {
+            let defaults = Value::try_from(Self::default())?;
+            let current = Value::try_from(self)?;
+            let minimal = minimal_value(&current, Some(&defaults)).unwrap_or_else(|| Value::Table(Default::default()));
 
-        let mut input = String::new();
-        match reader.read_line(&mut input) {
-            Ok(_) => Ok(input.trim() == expected),
-            Err(e) => Err(Error::with_chain(e, ErrorKind::FailedToReadConfirmation)),
+            to_toml_string(&minimal)
         }
-    }
 
-    pub fn set_color_off() -> () {
-        set_color(false);
-    }
+        /// Compares `file_path` as it is on disk with `self` and returns what `save` would change, without writing.
+        fn save_diff<T: AsRef<Path>>(&self, file_path: T) -> ConfigResult<Vec<FieldChange>> where Self: Serialize {
+            let on_disk = if file_path.as_ref().exists() { read_value(file_path)? } else { Value::Table(Default::default()) };
+            let current = Value::try_from(self)?;
 
-    pub fn set_color(on: bool) -> () {
-        colored::control::set_override(on); 
-    }
+            Ok(field_changes(&on_disk, &current))
+        }
 
-    error_chain! {
-        errors {
-            FailedToReadConfirmation {
-                description("Failed to read confirmation")
+        /// Re-reads `file_path` into `self` and returns the changed fields so callers can react selectively;
+        /// if the file cannot be loaded, `self` is kept as is and the error is returned.
+        fn reload<T: AsRef<Path>>(&mut self, file_path: T) -> ConfigResult<Vec<FieldChange>> where Self: Sized + Serialize + DeserializeOwned {
+            self.reload_validated(file_path, |_| Ok(()))
+        }
+
+        /// Like `reload` but only swaps in the new config if `validate` accepts it; otherwise `self` is kept,
+        /// the rejection is logged as an error and returned as `ConfigErrorKind::InvalidConfig`.
+        fn reload_validated<T: AsRef<Path>, F: FnOnce(&Self) -> Result<(), String>>(&mut self, file_path: T, validate: F) -> ConfigResult<Vec<FieldChange>>
+            where Self: Sized + Serialize + DeserializeOwned
+        {
+            let file_path = file_path.as_ref();
+            let raw = read_value(file_path)?;
+            let reloaded: Self = raw.try_into()?;
+            if let Err(reason) = validate(&reloaded) {
+                log::error!("Keeping current configuration because '{}' is invalid: {}", file_path.display(), reason);
+                return Err(ConfigErrorKind::InvalidConfig(file_path.to_string_lossy().to_string(), reason).into());
             }
+            let changes = field_changes(&Value::try_from(&*self)?, &Value::try_from(&reloaded)?);
+            *self = reloaded;
+
+            Ok(changes)
         }
-    }
 
-    #[cfg(test)]
-    mod test {
-        use super::*;
+        /// Deep-merges `other` onto `self` like a config file overlay: scalars replace, `Option`s replace only when
+        /// `Some` and nested structs merge field by field; `vecs` decides whether arrays replace or append.
+        fn merge(&mut self, other: Self, vecs: VecMerge) -> ConfigResult<()> where Self: Sized + Serialize + DeserializeOwned {
+            let mut merged = Value::try_from(&*self)?;
+            merge_values_with(&mut merged, Value::try_from(other)?, vecs);
+            *self = merged.try_into()?;
 
-        use quickcheck::{quickcheck, TestResult};
-        use spectral::prelude::*;
-        use std::io::BufWriter;
+            Ok(())
+        }
 
-        #[test]
-        fn ask_for_yes_from_okay() {
-            let answer = "yes".to_owned();
-            let mut input = BufReader::new(answer.as_bytes());
-            let output_buf = Vec::new();
-            let mut output = BufWriter::new(output_buf);
+        /// Loads `file_path` and reports unknown keys and loose file permissions; they are logged as warnings or,
+        /// if `strict`, returned together as `ConfigErrorKind::Warnings`.
+        fn load_checked<T: AsRef<Path>>(file_path: T, strict: bool) -> ConfigResult<Self> where Self: Sized + Serialize + DeserializeOwned {
+            let file_path = file_path.as_ref();
+            let raw = read_value(file_path)?;
+            let config: Self = raw.clone().try_into()?;
 
-            let res = ask_for_confirmation_from(&mut input, &mut output, "This is just a test prompt: ", "yes");
+            let mut warnings = Warnings::new(strict);
+            let mut unknown = Vec::new();
+            collect_unknown_keys("", &raw, &Value::try_from(&config)?, &mut unknown);
+            for key in unknown {
+                warnings.warn(format!("unknown field '{}' in '{}'", key, file_path.display()));
+            }
+            if let Err(e) = check_permissions(file_path) {
+                warnings.warn(e.to_string());
+            }
+            warnings.finish()?;
 
-            assert_that(&res).is_ok().is_true();
+            Ok(config)
         }
 
-        #[test]
-        fn ask_for_yes_reader_quick() {
-            fn prop(x: String) -> TestResult {
-                let expected = "yes";
+        /// Parses `file_path` and merges it onto `base`; values present in the file win, tables merge recursively.
+        fn load_over<T: AsRef<Path>>(file_path: T, base: Self) -> ConfigResult<Self> where Self: Sized + Serialize + DeserializeOwned {
+            let mut merged = Value::try_from(base)?;
+            merge_values(&mut merged, read_value(file_path)?);
 
-                if x.len() > 3 || x == expected {
-                    return TestResult::discard();
-                }
+            Ok(merged.try_into()?)
+        }
 
-                let mut input = BufReader::new(x.as_bytes());
-                let output_buf = Vec::new();
-                let mut output = BufWriter::new(output_buf);
+        /// Loads `base_path` and, if present, merges the overlay `<stem>.<env_name>.<ext>` next to it.
+        fn load_for_env<T: AsRef<Path>>(base_path: T, env_name: &str) -> ConfigResult<Self> where Self: Sized + DeserializeOwned {
+            let base_path = base_path.as_ref();
+            let mut merged = read_value(base_path)?;
+            let overlay_path = env_overlay_path(base_path, env_name);
+            if overlay_path.exists() {
+                merge_values(&mut merged, read_value(&overlay_path)?);
+            }
 
-                let res = ask_for_confirmation_from(&mut input, &mut output, "This is just a test prompt: ", expected)
-                    .unwrap();
+            Ok(merged.try_into()?)
+        }
 
-                TestResult::from_bool(res == false)
-            }
+        /// Loads `file_path` and overrides fields from environment variables named `<PREFIX>_<SECTION>_<FIELD>` in
+        /// upper case, e.g. `MYAPP_GENERAL_NAME`. Strings are taken verbatim, other values are parsed as TOML, e.g.
+        /// `8080` or `["a", "b"]`; integers are accepted for floats. Fields missing from the file, e.g. unset `Option`s,
+        /// are set, too, if declared by the struct, using their serialized name, e.g. `MYAPP_SERVER_MAXCONNECTIONS` for
+        /// a field renamed to `maxConnections`; fields of `#[serde(flatten)]`ed structs must be present in the file.
+        fn from_file_with_env<T: AsRef<Path>>(file_path: T, prefix: &str) -> ConfigResult<Self> where Self: Sized + Serialize + DeserializeOwned {
+            let config: Self = read_value(file_path)?.try_into()?;
+            let value = Value::try_from(config)?;
+            let prefix = prefix.to_uppercase();
+            let vars = env_vars_with_prefix(&prefix);
+            let fields = FieldTree::of::<Self>();
 
-            quickcheck(prop as fn(String) -> TestResult);
+            let mut parsed = value.clone();
+            apply_env_overrides(&mut parsed, &prefix, &fields, &vars, false)?;
+            let err = match parsed.try_into() {
+                Ok(config) => return Ok(config),
+                Err(err) => err,
+            };
+            // Missing fields have no known type, so retry them verbatim, e.g. an `Option<String>` set to `1234`.
+            let mut verbatim = value;
+            apply_env_overrides(&mut verbatim, &prefix, &fields, &vars, true)?;
+
+            verbatim.try_into().map_err(|_| err.into())
         }
-    }
-}
 
-pub mod fs {
-    use std::io::{BufReader, BufWriter};
-    use std::env;
-    use std::fs::File;
-    use std::path::{Path, PathBuf};
-    use tail;
+        /// Deep-merges all existing candidates, which are ordered like for `smart_load`, e.g. `default_locations`:
+        /// earlier files override later ones. Returns the contributing files in candidate order; an existing but
+        /// invalid file is an error instead of being skipped.
+        fn smart_merge<T: AsRef<Path>>(file_paths: &[T]) -> ConfigResult<(Self, Vec<&Path>)> where Self: Sized + DeserializeOwned {
+            let existing: Vec<&Path> = file_paths.iter().map(AsRef::as_ref).filter(|path| path.exists()).collect();
+            if existing.is_empty() {
+                let candidates = file_paths.iter().map(|path| path.as_ref().to_string_lossy().to_string()).collect();
+                return Err(no_suitable_config(candidates));
+            }
 
-    pub fn file_exists<T: AsRef<Path>>(path: T) -> bool {
-        path.as_ref().exists()
-    }
+            let mut merged = Value::Table(Default::default());
+            for path in existing.iter().rev() {
+                merge_values(&mut merged, read_value(path)?);
+            }
 
-    pub fn home_dir() -> Option<PathBuf> {
-        env::home_dir()
-    }
+            Ok((merged.try_into()?, existing))
+        }
 
-    pub trait FileExt {
-        fn read_last_line(self) -> ::std::io::Result<String>;
-    }
+        /// Merges all config files in `dir` ordered by their optional top-level `priority` (ties broken by path); higher priorities win.
+        fn from_fragments_dir<T: AsRef<Path>>(dir: T) -> ConfigResult<Self> where Self: Sized + DeserializeOwned {
+            let mut fragments = Vec::new();
+            for path in list_files(dir, None)? {
+                let is_config = path.extension().and_then(|extension| extension.to_str()).and_then(Format::from_extension).is_some();
+                if !is_config {
+                    continue;
+                }
+                let mut value = read_value(&path)?;
+                let priority = take_priority(&mut value, &path)?;
+                fragments.push((priority, path, value));
+            }
+            fragments.sort_by(|(a_priority, a_path, _), (b_priority, b_path, _)| (a_priority, a_path).cmp(&(b_priority, b_path)));
 
-    impl FileExt for File {
-        fn read_last_line(self) -> ::std::io::Result<String> {
-            let mut fd = BufReader::new(self);
-            let mut reader = tail::BackwardsReader::new(10, &mut fd);
-            let mut buffer = String::new();
-            {
-                let mut writer = BufWriter::new(
-                    unsafe {
-                        buffer.as_mut_vec()
-                    }
-                );
-                reader.read_all(&mut writer);
+            let mut merged = Value::Table(Default::default());
+            for (_, _, value) in fragments {
+                merge_values(&mut merged, value);
             }
-            let line = buffer.lines().last().map(|s| s.to_owned()).unwrap_or_else(|| String::new());
-            Ok(line)
+
+            Ok(merged.try_into()?)
         }
-    }
 
-    #[cfg(test)]
-    mod test {
-        pub use super::*;
-        pub use spectral::prelude::*;
+        /// Loads `defaults_path` and overlays `user_path`; a missing user file yields the pure defaults.
+        fn from_file_with_defaults<T: AsRef<Path>, U: AsRef<Path>>(defaults_path: T, user_path: U) -> ConfigResult<Self> where Self: Sized + DeserializeOwned {
+            let mut merged = read_value(defaults_path)?;
+            if user_path.as_ref().exists() {
+                merge_values(&mut merged, read_value(user_path)?);
+            }
 
-        mod file_exists {
-            use super::*;
+            Ok(merged.try_into()?)
+        }
 
-            #[test]
-            fn no_such_file() {
-                let file_name = "no_such.file";
-                let res = file_exists(&file_name);
-                assert_that(&res).is_false();
+        fn reset_field(&mut self, path: &str) -> ConfigResult<()> where Self: Default + Serialize + DeserializeOwned {
+            if path.is_empty() {
+                *self = Self::default();
+                return Ok(());
             }
 
-            #[test]
-            fn file_does_exists() {
-                let file_name = "tests/data/file.exists";
-                let res = file_exists(&file_name);
-                assert_that(&res).is_true();
+            let defaults = Value::try_from(Self::default())?;
+            let mut current = Value::try_from(&*self)?;
+            let (parent, key) = match path.rfind('.') {
+                Some(pos) => (&path[..pos], &path[pos + 1..]),
+                None => ("", path),
+            };
+            let table = value_at_mut(&mut current, parent)
+                .and_then(Value::as_table_mut)
+                .ok_or_else(|| ConfigErrorKind::NoSuchField(path.to_owned()))?;
+            match value_at(&defaults, path) {
+                Some(default) => { table.insert(key.to_owned(), default.clone()); }
+                None => {
+                    table.remove(key).ok_or_else(|| ConfigErrorKind::NoSuchField(path.to_owned()))?;
+                }
             }
+            *self = current.try_into()?;
+
+            Ok(())
         }
+    }
 
-        mod file_ext {
-            use super::*;
+    fn read_value<T: AsRef<Path>>(file_path: T) -> ConfigResult<Value> {
+        let format = Format::from_path(&file_path);
+        let content = fs::read_to_string(file_path)?;
 
-            #[test]
-            fn read_last_line_okay() {
-                let file = File::open("tests/data/tail.txt").expect("Could not open tail.txt");
+        format.parse(&content)
+    }
 
-                let last_line = file.read_last_line().expect("Could not read last line");
+    const FRAGMENT_PRIORITY_KEY: &str = "priority";
 
-                assert_that(&last_line).is_equal_to("-- Marcus Marcus Aurelius".to_owned());
-            }
+    /// Removes the top-level priority key so it does not leak into the merged config.
+    fn take_priority(fragment: &mut Value, path: &Path) -> ConfigResult<i64> {
+        let priority = match fragment {
+            Value::Table(table) => table.remove(FRAGMENT_PRIORITY_KEY),
+            _ => None,
+        };
+        match priority {
+            None => Ok(0),
+            Some(Value::Integer(priority)) => Ok(priority),
+            Some(_) => Err(ConfigErrorKind::InvalidPriority(path.to_string_lossy().to_string()).into()),
         }
     }
-}
-
-pub mod logging {
-    use error_chain::*;
-    use fern::{Dispatch, Output};
-    use fern::colors::{Color, ColoredLevelConfig};
-    use log;
 
-    #[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
-    pub struct Level(pub log::LevelFilter);
+    /// Keeps the field order but falls back to a `Value` round-trip when plain values follow a table, e.g. with
+    /// `#[serde(flatten)]` fields declared after sections.
+    fn to_toml_string<T: Serialize + ?Sized>(value: &T) -> ConfigResult<String> {
+        match toml::to_string_pretty(value) {
+            Err(toml::ser::Error::ValueAfterTable) => Ok(toml::to_string_pretty(&Value::try_from(value)?)?),
+            res => Ok(res?),
+        }
+    }
 
-    impl From<u64> for Level {
-        fn from(level: u64) -> Self {
-            match level {
-                0 => Level(log::LevelFilter::Warn),
-                1 => Level(log::LevelFilter::Info),
-                2 => Level(log::LevelFilter::Debug),
-                _ => Level(log::LevelFilter::Trace),
-            }
+    fn read_limited<R: Read>(reader: R, max_bytes: usize) -> ConfigResult<String> {
+        let mut content = Vec::new();
+        reader.take((max_bytes as u64).saturating_add(1)).read_to_end(&mut content)?;
+        if content.len() > max_bytes {
+            return Err(ConfigErrorKind::TooLarge(max_bytes).into());
         }
+
+        String::from_utf8(content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
     }
 
-    #[derive(Debug)]
-    pub struct ModLevel {
-        pub module: String,
-        pub level: Level,
+    /// Environment variables named `<prefix>_...`; variables that are not valid unicode are skipped.
+    fn env_vars_with_prefix(prefix: &str) -> BTreeMap<String, String> {
+        let prefix = format!("{}_", prefix);
+        env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .filter(|(name, _)| name.starts_with(&prefix))
+            .collect()
     }
 
-    #[derive(Debug)]
-    pub struct LogConfig {
-        out: Output,
-        color: bool,
-        default: Level,
-        levels: Vec<ModLevel>,
-        context: Option<String>,
+    /// Field names per section as declared by a `Deserialize` implementation, i.e. including renames and unset `Option`s.
+    #[derive(Debug, Default, PartialEq)]
+    struct FieldTree(BTreeMap<String, FieldTree>);
+
+    impl FieldTree {
+        /// Probes `T::deserialize` with placeholder values. Fields of `#[serde(flatten)]`ed structs and of structs
+        /// following a field that rejects its placeholder, e.g. an enum, are not discovered.
+        fn of<T: DeserializeOwned>() -> FieldTree {
+            let mut tree = FieldTree::default();
+            let _ = T::deserialize(FieldProbe { tree: &mut tree, depth: 0 });
+            tree
+        }
     }
 
-    impl LogConfig {
-        pub fn new<T: Into<Output>>(out: T, color: bool, default: Level, levels: Vec<ModLevel>, context: Option<String>) -> Self {
-            LogConfig {
-                out: out.into(),
-                color,
-                default,
-                levels,
-                context,
+    /// Bounds the probe for recursive types like `Option<Box<Self>>`.
+    const MAX_FIELD_DEPTH: usize = 16;
+
+    struct FieldProbe<'a> {
+        tree: &'a mut FieldTree,
+        depth: usize,
+    }
+
+    macro_rules! probe_with {
+        ($($method:ident => $visit:ident($($arg:expr),*);)*) => {
+            $(fn $method<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                visitor.$visit($($arg),*)
+            })*
+        };
+    }
+
+    impl<'de, 'a> de::Deserializer<'de> for FieldProbe<'a> {
+        type Error = de::value::Error;
+
+        probe_with! {
+            deserialize_any => visit_unit();
+            deserialize_ignored_any => visit_unit();
+            deserialize_unit => visit_unit();
+            deserialize_bool => visit_bool(false);
+            deserialize_i8 => visit_i64(0);
+            deserialize_i16 => visit_i64(0);
+            deserialize_i32 => visit_i64(0);
+            deserialize_i64 => visit_i64(0);
+            deserialize_u8 => visit_u64(0);
+            deserialize_u16 => visit_u64(0);
+            deserialize_u32 => visit_u64(0);
+            deserialize_u64 => visit_u64(0);
+            deserialize_f32 => visit_f64(0.0);
+            deserialize_f64 => visit_f64(0.0);
+            deserialize_char => visit_char(' ');
+            deserialize_str => visit_str("");
+            deserialize_string => visit_str("");
+            deserialize_identifier => visit_str("");
+            deserialize_bytes => visit_bytes(&[]);
+            deserialize_byte_buf => visit_bytes(&[]);
+            deserialize_seq => visit_seq(de::value::SeqDeserializer::new(std::iter::empty::<()>()));
+            deserialize_map => visit_map(de::value::MapDeserializer::new(std::iter::empty::<((), ())>()));
+        }
+
+        fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+            visitor.visit_some(self)
+        }
+
+        fn deserialize_unit_struct<V: de::Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+            visitor.visit_unit()
+        }
+
+        fn deserialize_newtype_struct<V: de::Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+            visitor.visit_newtype_struct(self)
+        }
+
+        fn deserialize_tuple<V: de::Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, Self::Error> {
+            self.deserialize_seq(visitor)
+        }
+
+        fn deserialize_tuple_struct<V: de::Visitor<'de>>(self, _: &'static str, _: usize, visitor: V) -> Result<V::Value, Self::Error> {
+            self.deserialize_seq(visitor)
+        }
+
+        fn deserialize_enum<V: de::Visitor<'de>>(self, name: &'static str, _: &'static [&'static str], _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom(format!("cannot probe enum {}", name)))
+        }
+
+        fn deserialize_struct<V: de::Visitor<'de>>(self, _: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+            if self.depth >= MAX_FIELD_DEPTH {
+                return Err(de::Error::custom("too deeply nested"));
             }
+            for field in fields {
+                self.tree.0.entry((*field).to_owned()).or_default();
+            }
+            visitor.visit_map(FieldProbeMap { tree: self.tree, depth: self.depth + 1, fields: fields.iter(), current: "" })
         }
     }
 
+    struct FieldProbeMap<'a> {
+        tree: &'a mut FieldTree,
+        depth: usize,
+        fields: std::slice::Iter<'static, &'static str>,
+        current: &'static str,
+    }
 
-    pub fn init_logging(log_config: LogConfig) -> Result<()> {
-        let Level(default) = log_config.default;
-        let mut log_levels = Dispatch::new().level(default);
+    impl<'de, 'a> de::MapAccess<'de> for FieldProbeMap<'a> {
+        type Error = de::value::Error;
 
-        for md in log_config.levels.into_iter() {
-            let ModLevel { module, level } = md;
-            let Level(level) = level;
-            log_levels = log_levels.level_for(module, level);
+        fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+            match self.fields.next() {
+                Some(field) => {
+                    self.current = field;
+                    seed.deserialize(de::value::BorrowedStrDeserializer::new(field)).map(Some)
+                }
+                None => Ok(None),
+            }
+        }
+
+        fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+            let tree = self.tree.0.entry(self.current.to_owned()).or_default();
+            seed.deserialize(FieldProbe { tree, depth: self.depth })
+        }
+    }
+
+    /// Overrides the fields of `value` from `vars`. Fields of `fields` missing from a table are added from their
+    /// variables, as strings if `verbatim` or else parsed like TOML where possible, and missing sections are created.
+    fn apply_env_overrides(value: &mut Value, name: &str, fields: &FieldTree, vars: &BTreeMap<String, String>, verbatim: bool) -> ConfigResult<()> {
+        if let Value::Table(table) = value {
+            let unknown = FieldTree::default();
+            for (key, value) in table.iter_mut() {
+                let name = format!("{}_{}", name, key.to_uppercase());
+                apply_env_overrides(value, &name, fields.0.get(key).unwrap_or(&unknown), vars, verbatim)?;
+            }
+
+            for (field, subfields) in &fields.0 {
+                if table.contains_key(field) {
+                    continue;
+                }
+                let name = format!("{}_{}", name, field.to_uppercase());
+                let missing = if subfields.0.is_empty() {
+                    match vars.get(&name) {
+                        Some(raw) if verbatim => Value::String(raw.clone()),
+                        Some(raw) => parse_env_value(raw).unwrap_or_else(|_| Value::String(raw.clone())),
+                        None => continue,
+                    }
+                } else {
+                    let mut section = Value::Table(Default::default());
+                    apply_env_overrides(&mut section, &name, subfields, vars, verbatim)?;
+                    if section.as_table().map(|section| section.is_empty()).unwrap_or(true) {
+                        continue;
+                    }
+                    section
+                };
+                table.insert(field.clone(), missing);
+            }
+
+            return Ok(());
         }
-        log_levels = log_levels.chain(log_config.out);
+        let raw = match vars.get(name) {
+            Some(raw) => raw.clone(),
+            None => return Ok(()),
+        };
 
-        let format = if log_config.color {
-            format_with_color(log_config.context)
+        let overridden = if value.is_str() {
+            Value::String(raw)
         } else {
-            format_no_color(log_config.context)
+            let invalid = |reason: String| ConfigErrorKind::InvalidEnvVar(name.to_owned(), reason);
+            let parsed = parse_env_value(&raw).map_err(invalid)?;
+            match parsed {
+                Value::Integer(i) if value.is_float() => Value::Float(i as f64),
+                parsed if parsed.type_str() != value.type_str() =>
+                    return Err(invalid(format!("expected {}, found {}", value.type_str(), parsed.type_str())).into()),
+                parsed => parsed,
+            }
         };
-        format
-            .chain(log_levels)
-            .apply()
-            .map_err(|e| Error::with_chain(e, ErrorKind::FailedToInitLogging))?;
+        *value = overridden;
 
         Ok(())
     }
 
-    fn format_with_color(context: Option<String>) -> Dispatch {
-        let colors = ColoredLevelConfig::new()
-            .info(Color::Green)
-            .debug(Color::Blue);
-        let context = if let Some(c) = context {
-            format!("[Context: {}] ", c)
-        } else {
-            "".to_owned()
+    fn parse_env_value(raw: &str) -> Result<Value, String> {
+        let parsed: Value = toml::from_str(&format!("value = {}", raw)).map_err(|e| e.to_string())?;
+        parsed.get("value").cloned().ok_or_else(|| "no value".to_owned())
+    }
+
+    fn env_overlay_path(base_path: &Path, env_name: &str) -> PathBuf {
+        let stem = base_path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+        let file_name = match base_path.extension() {
+            Some(extension) => format!("{}.{}.{}", stem, env_name, extension.to_string_lossy()),
+            None => format!("{}.{}", stem, env_name),
         };
-        Dispatch::new()
-            .format(move |out, message, record| {
-                let level = format!("{}", record.level());
-                out.finish(format_args!(
-                    "{}{}{:padding$}{}: {}",
-                    context,
-                    colors.color(record.level()),
-                    " ",
-                    record.target(),
-                    message,
-                    padding = 6 - level.len(),
-                ))
+
+        base_path.with_file_name(file_name)
+    }
+
+    /// Collects config warnings and either logs them or, if strict, turns them into one error.
+    struct Warnings {
+        strict: bool,
+        messages: Vec<String>,
+    }
+
+    impl Warnings {
+        fn new(strict: bool) -> Self {
+            Warnings { strict, messages: Vec::new() }
+        }
+
+        fn warn(&mut self, message: String) {
+            self.messages.push(message);
+        }
+
+        fn finish(self) -> ConfigResult<()> {
+            if self.strict && !self.messages.is_empty() {
+                return Err(ConfigErrorKind::Warnings(self.messages).into());
+            }
+            for message in self.messages {
+                log::warn!("{}", message);
+            }
+
+            Ok(())
+        }
+    }
+
+    fn collect_unknown_keys(path: &str, raw: &Value, known: &Value, unknown: &mut Vec<String>) {
+        if let (Value::Table(raw), Value::Table(known)) = (raw, known) {
+            for (key, value) in raw {
+                let path = if path.is_empty() { key.to_owned() } else { format!("{}.{}", path, key) };
+                match known.get(key) {
+                    Some(known) => collect_unknown_keys(&path, value, known, unknown),
+                    None => unknown.push(path),
+                }
+            }
+        }
+    }
+
+    fn minimal_value(current: &Value, default: Option<&Value>) -> Option<Value> {
+        match (current, default) {
+            (Value::Table(current), Some(Value::Table(default))) => {
+                let minimal: toml::value::Table = current.iter()
+                    .filter_map(|(key, value)| minimal_value(value, default.get(key)).map(|value| (key.to_owned(), value)))
+                    .collect();
+                if minimal.is_empty() { None } else { Some(Value::Table(minimal)) }
+            }
+            (current, Some(default)) if current == default => None,
+            (current, _) => Some(current.clone()),
+        }
+    }
+
+    /// How `Config::merge` combines arrays present on both sides.
+    #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+    pub enum VecMerge {
+        #[default]
+        Replace,
+        Append,
+    }
+
+    fn decode_base64(encoded: &str) -> Result<String, String> {
+        use base64::Engine;
+
+        let bytes = base64::engine::general_purpose::STANDARD.decode(encoded).map_err(|e| e.to_string())?;
+        String::from_utf8(bytes).map_err(|e| e.to_string())
+    }
+
+    fn merge_values(base: &mut Value, overlay: Value) {
+        merge_values_with(base, overlay, VecMerge::Replace)
+    }
+
+    fn merge_values_with(base: &mut Value, overlay: Value, vecs: VecMerge) {
+        match (base, overlay) {
+            (Value::Table(base), Value::Table(overlay)) => {
+                for (key, value) in overlay {
+                    match base.get_mut(&key) {
+                        Some(existing) => merge_values_with(existing, value, vecs),
+                        None => { base.insert(key, value); }
+                    }
+                }
+            }
+            (Value::Array(base), Value::Array(overlay)) if vecs == VecMerge::Append => base.extend(overlay),
+            (base, overlay) => *base = overlay,
+        }
+    }
+
+    /// A changed field by dotted path with its old and new value rendered as TOML; `None` if absent.
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub struct FieldChange {
+        pub path: String,
+        pub old: Option<String>,
+        pub new: Option<String>,
+    }
+
+    fn field_changes(old: &Value, new: &Value) -> Vec<FieldChange> {
+        let mut changes = Vec::new();
+        diff_values("", Some(old), Some(new), &mut changes);
+
+        changes.into_iter()
+            .map(|(path, old, new)| FieldChange {
+                path,
+                old: old.map(ToString::to_string),
+                new: new.map(ToString::to_string),
             })
+            .collect()
+    }
+
+    type ValueChange<'a> = (String, Option<&'a Value>, Option<&'a Value>);
+
+    fn diff_values<'a>(path: &str, old: Option<&'a Value>, new: Option<&'a Value>, changes: &mut Vec<ValueChange<'a>>) {
+        match (old, new) {
+            (Some(Value::Table(old)), Some(Value::Table(new))) => {
+                let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+                for key in keys {
+                    let path = if path.is_empty() { key.to_owned() } else { format!("{}.{}", path, key) };
+                    diff_values(&path, old.get(key), new.get(key), changes);
+                }
+            }
+            (old, new) if old != new => changes.push((path.to_owned(), old, new)),
+            _ => {}
+        }
+    }
+
+    fn value_at<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+        if path.is_empty() {
+            return Some(value);
+        }
+        path.split('.').try_fold(value, |value, key| value.get(key))
+    }
+
+    fn value_at_mut<'a>(value: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+        if path.is_empty() {
+            return Some(value);
+        }
+        path.split('.').try_fold(value, |value, key| value.get_mut(key))
+    }
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub enum Format {
+        Toml,
+        Yaml,
+        Json,
+        /// A format added with `register_format`, identified by its lowercase extension.
+        Custom(&'static str),
+    }
+
+    /// Parses file content into the intermediate value configs are deserialized from.
+    pub type FormatParser = fn(&str) -> ConfigResult<Value>;
+
+    static CUSTOM_FORMATS: RwLock<BTreeMap<&'static str, FormatParser>> = RwLock::new(BTreeMap::new());
+
+    /// Makes files with `extension` loadable by the format-detecting loaders such as `from_file_detect`, `load_over`
+    /// or `from_fragments_dir`; built-in formats take precedence and custom formats cannot be saved.
+    pub fn register_format(extension: &str, parser: FormatParser) {
+        let extension = extension.to_lowercase();
+        let mut formats = CUSTOM_FORMATS.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(parser_slot) = formats.get_mut(extension.as_str()) {
+            *parser_slot = parser;
+            return;
+        }
+        formats.insert(Box::leak(extension.into_boxed_str()), parser);
+    }
+
+    fn custom_format(extension: &str) -> Option<(&'static str, FormatParser)> {
+        let formats = CUSTOM_FORMATS.read().unwrap_or_else(|e| e.into_inner());
+        formats.get_key_value(extension).map(|(extension, parser)| (*extension, *parser))
     }
 
-    fn format_no_color(context: Option<String>) -> Dispatch {
-        let context = if let Some(c) = context {
-            format!("[Context: {}] ", c)
+    impl Format {
+        pub fn from_extension(extension: &str) -> Option<Format> {
+            match extension.to_lowercase().as_str() {
+                "toml" => Some(Format::Toml),
+                "yaml" | "yml" => Some(Format::Yaml),
+                "json" => Some(Format::Json),
+                extension => custom_format(extension).map(|(extension, _)| Format::Custom(extension)),
+            }
+        }
+
+        pub fn from_content_type(content_type: &str) -> Option<Format> {
+            let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+            match mime.as_str() {
+                "application/toml" | "text/toml" | "text/x-toml" => Some(Format::Toml),
+                "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => Some(Format::Yaml),
+                "application/json" => Some(Format::Json),
+                _ => None,
+            }
+        }
+
+        /// Detects the format by file extension, falling back to TOML.
+        pub fn from_path<T: AsRef<Path>>(path: T) -> Format {
+            path.as_ref().extension()
+                .and_then(|extension| extension.to_str())
+                .and_then(Format::from_extension)
+                .unwrap_or(Format::Toml)
+        }
+
+        fn parse<T: DeserializeOwned>(self, content: &str) -> ConfigResult<T> {
+            match self {
+                Format::Toml => Ok(toml::from_str(content)?),
+                Format::Yaml => {
+                    let mut value: serde_yaml::Value = serde_yaml::from_str(content)?;
+                    remove_yaml_nulls(&mut value);
+                    Ok(serde_yaml::from_value(value)?)
+                }
+                Format::Json => {
+                    let mut value: serde_json::Value = serde_json::from_str(content)?;
+                    remove_json_nulls(&mut value);
+                    Ok(serde_json::from_value(value)?)
+                }
+                Format::Custom(extension) => {
+                    let (_, parser) = custom_format(extension)
+                        .ok_or_else(|| ConfigErrorKind::UnsupportedFormat(extension.to_owned()))?;
+                    Ok(parser(content)?.try_into()?)
+                }
+            }
+        }
+
+        fn serialize<T: Serialize + ?Sized>(self, value: &T) -> ConfigResult<String> {
+            match self {
+                Format::Toml => to_toml_string(value),
+                Format::Yaml => Ok(serde_yaml::to_string(value)?),
+                Format::Json => Ok(serde_json::to_string_pretty(value)? + "\n"),
+                Format::Custom(extension) => Err(ConfigErrorKind::UnsupportedFormat(extension.to_owned()).into()),
+            }
+        }
+    }
+
+    /// Drops `null` mapping entries, e.g. saved `None`s, since the intermediate TOML values have no null.
+    fn remove_yaml_nulls(value: &mut serde_yaml::Value) {
+        match value {
+            serde_yaml::Value::Mapping(mapping) => {
+                mapping.retain(|_, value| !value.is_null());
+                mapping.values_mut().for_each(remove_yaml_nulls);
+            }
+            serde_yaml::Value::Sequence(sequence) => sequence.iter_mut().for_each(remove_yaml_nulls),
+            _ => {}
+        }
+    }
+
+    fn remove_json_nulls(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(object) => {
+                object.retain(|_, value| !value.is_null());
+                object.values_mut().for_each(remove_json_nulls);
+            }
+            serde_json::Value::Array(array) => array.iter_mut().for_each(remove_json_nulls),
+            _ => {}
+        }
+    }
+
+    /// Serde helper storing `chrono::DateTime<Utc>` as a native TOML datetime; use with
+    /// `#[serde(with = "clams::config::toml_datetime")]`. RFC 3339 strings are accepted on load.
+    pub mod toml_datetime {
+        use chrono::{DateTime, SecondsFormat, Utc};
+        use serde::de::{self, Deserialize, Deserializer};
+        use serde::ser::{self, Serialize, Serializer};
+        use std::str::FromStr;
+        use toml::Value;
+        use toml::value::Datetime;
+
+        pub fn serialize<S: Serializer>(datetime: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+            let datetime = Datetime::from_str(&datetime.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+                .map_err(ser::Error::custom)?;
+            datetime.serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+            let datetime = match Value::deserialize(deserializer)? {
+                Value::Datetime(datetime) => datetime.to_string(),
+                Value::String(datetime) => datetime,
+                other => return Err(de::Error::custom(format!("expected a datetime, found {}", other.type_str()))),
+            };
+            DateTime::parse_from_rfc3339(&datetime)
+                .map(|datetime| datetime.with_timezone(&Utc))
+                .map_err(de::Error::custom)
+        }
+    }
+
+    /// Resolves `env:NAME`, `file:PATH` and `cmd:COMMAND` references to the secret they point to;
+    /// a single trailing newline is stripped and strings without a known scheme are returned unchanged.
+    pub fn resolve_secret_ref(reference: &str) -> ConfigResult<String> {
+        let unavailable = |reason: String| ConfigErrorKind::SecretUnavailable(reference.to_owned(), reason);
+
+        let secret = if let Some(name) = reference.strip_prefix("env:") {
+            return env::var(name).map_err(|e| unavailable(e.to_string()).into());
+        } else if let Some(path) = reference.strip_prefix("file:") {
+            fs::read_to_string(path).map_err(|e| unavailable(e.to_string()))?
+        } else if let Some(command) = reference.strip_prefix("cmd:") {
+            let output = shell(command).output().map_err(|e| unavailable(e.to_string()))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(unavailable(format!("command failed with {}: {}", output.status, stderr.trim())).into());
+            }
+            String::from_utf8(output.stdout).map_err(|e| unavailable(e.to_string()))?
         } else {
-            "".to_owned()
+            return Ok(reference.to_owned());
         };
-        Dispatch::new()
-            .format(move |out, message, record| {
-                let level = format!("{}", record.level());
-                out.finish(format_args!(
-                    "{}{}{:padding$}{}: {}",
-                    context,
-                    record.level(),
-                    " ",
-                    record.target(),
-                    message,
-                    padding = 6 - level.len(),
-                ))
-            })
+
+        let trimmed = secret.strip_suffix('\n').map(|s| s.strip_suffix('\r').unwrap_or(s)).unwrap_or(&secret);
+        Ok(trimmed.to_owned())
     }
 
-    error_chain! {
-        errors {
-            FailedToInitLogging {
-                description("Failed to init logging")
+    #[cfg(unix)]
+    fn shell(command: &str) -> process::Command {
+        let mut shell = process::Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+
+    #[cfg(not(unix))]
+    fn shell(command: &str) -> process::Command {
+        let mut shell = process::Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    }
+
+    /// Serde helper resolving secret references on load, see `resolve_secret_ref`; use with
+    /// `#[serde(deserialize_with = "clams::config::secret_ref::deserialize")]`.
+    pub mod secret_ref {
+        use serde::de::{self, Deserialize, Deserializer};
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+            let reference = String::deserialize(deserializer)?;
+            super::resolve_secret_ref(&reference).map_err(|e| de::Error::custom(e.kind().to_string()))
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    pub use self::encryption::KeySource;
+
+    #[cfg(feature = "encryption")]
+    mod encryption {
+        use super::{ConfigErrorKind, ConfigResult, Format};
+
+        use age::{Decryptor, Encryptor, IdentityFile};
+        use std::env;
+        use std::fmt;
+        use std::fs;
+        use std::io::{Read, Write};
+        use std::path::{Path, PathBuf};
+
+        /// Where to find the age identities, as generated by `age-keygen`, to decrypt and encrypt configs with.
+        #[derive(Clone, Debug, Eq, PartialEq)]
+        pub enum KeySource {
+            /// Environment variable holding one or more identities like `AGE-SECRET-KEY-1...`.
+            Env(String),
+            /// Identity file; comment lines are ignored.
+            File(PathBuf),
+        }
+
+        impl fmt::Display for KeySource {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self {
+                    KeySource::Env(name) => write!(f, "environment variable '{}'", name),
+                    KeySource::File(path) => write!(f, "file '{}'", path.display()),
+                }
+            }
+        }
+
+        fn identity_file(key_source: &KeySource) -> ConfigResult<IdentityFile<age::NoCallbacks>> {
+            let invalid = |reason: String| ConfigErrorKind::InvalidKey(key_source.to_string(), reason);
+            let content = match key_source {
+                KeySource::Env(name) => env::var(name).map_err(|e| invalid(e.to_string()))?,
+                KeySource::File(path) => fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?,
+            };
+
+            Ok(IdentityFile::from_buffer(content.as_bytes()).map_err(|e| invalid(e.to_string()))?)
+        }
+
+        pub fn decrypt(ciphertext: &[u8], key_source: &KeySource) -> ConfigResult<String> {
+            let identities = identity_file(key_source)?.into_identities()
+                .map_err(|e| ConfigErrorKind::InvalidKey(key_source.to_string(), e.to_string()))?;
+            let failed = |reason: String| ConfigErrorKind::DecryptionFailed(reason);
+
+            let decryptor = Decryptor::new_buffered(ciphertext).map_err(|e| failed(e.to_string()))?;
+            let mut reader = decryptor.decrypt(identities.iter().map(|identity| identity.as_ref()))
+                .map_err(|e| failed(e.to_string()))?;
+            let mut plaintext = String::new();
+            reader.read_to_string(&mut plaintext).map_err(|e| failed(e.to_string()))?;
+
+            Ok(plaintext)
+        }
+
+        pub fn encrypt(plaintext: &[u8], key_source: &KeySource) -> ConfigResult<Vec<u8>> {
+            let recipients = identity_file(key_source)?.to_recipients()
+                .map_err(|e| ConfigErrorKind::InvalidKey(key_source.to_string(), e.to_string()))?;
+            let failed = |reason: String| ConfigErrorKind::EncryptionFailed(reason);
+
+            let encryptor = Encryptor::with_recipients(recipients.iter().map(|recipient| recipient.as_ref() as &dyn age::Recipient))
+                .map_err(|e| failed(e.to_string()))?;
+            let mut ciphertext = Vec::with_capacity(plaintext.len());
+            let mut writer = encryptor.wrap_output(&mut ciphertext).map_err(|e| failed(e.to_string()))?;
+            writer.write_all(plaintext).and_then(|_| writer.finish()).map_err(|e| failed(e.to_string()))?;
+
+            Ok(ciphertext)
+        }
+
+        pub fn plain_format(path: &Path) -> Format {
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some(extension) if extension.eq_ignore_ascii_case("age") => Format::from_path(path.with_extension("")),
+                _ => Format::from_path(path),
             }
         }
     }
-}
 
-pub mod progress {
-    use indicatif::ProgressStyle;
+    #[cfg(feature = "remote")]
+    mod remote {
+        use super::{ConfigErrorKind, ConfigResult, Format, read_limited};
 
-    pub trait ProgressStyleExt {
-        fn default_clams_spinner() -> ProgressStyle;
+        use std::fs;
+        use std::path::Path;
+        use toml::Value;
+        use std::time::Duration;
 
-        fn default_clams_bar() -> ProgressStyle;
+        const TIMEOUT: Duration = Duration::from_secs(10);
+        const MAX_SIZE: usize = 10 * 1024 * 1024;
+
+        pub fn fetch(url: &str) -> ConfigResult<(String, Format)> {
+            let failed = |reason: String| ConfigErrorKind::FetchFailed(url.to_owned(), reason);
+
+            let response = ureq::AgentBuilder::new()
+                .timeout(TIMEOUT)
+                .build()
+                .get(url)
+                .call()
+                .map_err(|e| failed(e.to_string()))?;
+            let format = Format::from_content_type(response.content_type())
+                .unwrap_or_else(|| Format::from_path(Path::new(url.split(&['?', '#'][..]).next().unwrap_or(url))));
+            let content = read_limited(response.into_reader(), MAX_SIZE)?;
+
+            Ok((content, format))
+        }
+
+        /// Writes already parsed `content` to `cache`, converting it if the cache's extension implies another format.
+        pub fn write_cache(cache: &Path, content: &str, format: Format) -> ConfigResult<()> {
+            let cache_format = Format::from_path(cache);
+            if cache_format == format {
+                fs::write(cache, content)?;
+            } else {
+                let value: Value = format.parse(content)?;
+                fs::write(cache, cache_format.serialize(&value)?)?;
+            }
+
+            Ok(())
+        }
     }
 
-    impl ProgressStyleExt for ProgressStyle {
-        fn default_clams_spinner() -> ProgressStyle {
-            ProgressStyle::default_spinner()
-                .template("{prefix:.bold.dim} [{elapsed}] {spinner} {wide_msg}")
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub enum Backup {
+        /// Keeps a single `<file>.bak` which is replaced on every save.
+        Rolling,
+        /// Keeps up to `keep` copies named `<file>.bak.<UTC timestamp with milliseconds>`, removing the oldest;
+        /// `keep` must be at least 1.
+        Timestamped { keep: usize },
+    }
+
+    impl Backup {
+        fn validate(&self) -> ConfigResult<()> {
+            match *self {
+                Backup::Timestamped { keep: 0 } => Err(ConfigErrorKind::InvalidBackup("keep must be at least 1".to_owned()).into()),
+                _ => Ok(()),
+            }
         }
 
-        fn default_clams_bar() -> ProgressStyle {
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{bar:20.blue/blue}] {pos}/{len} ({eta}) {wide_msg} {spinner:.blue}")
+        fn path_for(&self, file_path: &Path) -> PathBuf {
+            let mut backup = file_path.as_os_str().to_owned();
+            match *self {
+                Backup::Rolling => backup.push(".bak"),
+                Backup::Timestamped { .. } => {
+                    let stamp = Utc::now().format("%Y-%m-%dT%H-%M-%S%.3f").to_string();
+                    backup.push(format!(".bak.{}", stamp));
+                    // Saves within the same millisecond must not overwrite each other's backup.
+                    let mut n = 1;
+                    while Path::new(&backup).exists() {
+                        backup = file_path.as_os_str().to_owned();
+                        backup.push(format!(".bak.{}_{}", stamp, n));
+                        n += 1;
+                    }
+                }
+            }
+            PathBuf::from(backup)
+        }
+
+        fn prune(&self, file_path: &Path) -> ConfigResult<()> {
+            let keep = match *self {
+                Backup::Rolling => return Ok(()),
+                Backup::Timestamped { keep } => keep,
+            };
+            let prefix = match file_path.file_name() {
+                Some(name) => format!("{}.bak.", name.to_string_lossy()),
+                None => return Ok(()),
+            };
+            let dir = match file_path.parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            };
+
+            let mut backups: Vec<((String, u64), PathBuf)> = fs::read_dir(dir)?
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    name.strip_prefix(&prefix).map(backup_order).map(|order| (order, entry.path()))
+                })
+                .collect();
+            backups.sort();
+            let surplus = backups.len().saturating_sub(keep);
+            for (_, old) in &backups[..surplus] {
+                fs::remove_file(old)?;
+            }
+
+            Ok(())
         }
+    }
 
+    /// Orders a timestamped backup suffix like `<stamp>_<n>` by stamp and then numerically by collision counter.
+    fn backup_order(suffix: &str) -> (String, u64) {
+        match suffix.rsplit_once('_') {
+            Some((stamp, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => (stamp.to_owned(), n.parse().unwrap_or(u64::MAX)),
+            _ => (suffix.to_owned(), 0),
+        }
     }
-}
 
+    #[cfg(not(windows))]
+    pub fn default_locations(config_file_name: &str) -> Vec<PathBuf> {
+        let mut locations: Vec<PathBuf> = Vec::new();
+
+        if let Some(mut path) = home_dir() {
+            let home_config = format!(".{}", config_file_name);
+            path.push(home_config);
+            locations.push(path);
+        }
+
+        let mut etc = PathBuf::new();
+        etc.push("/etc");
+        etc.push(config_file_name);
+        locations.push(etc);
+
+        locations
+    }
+
+    /// Returns `%APPDATA%\<app>\<file>` and `%PROGRAMDATA%\<app>\<file>`, where `<app>` is the file name without extension.
+    #[cfg(windows)]
+    pub fn default_locations(config_file_name: &str) -> Vec<PathBuf> {
+        let app = Path::new(config_file_name).file_stem().map(|stem| stem.to_os_string()).unwrap_or_default();
+        let program_data = env::var_os("PROGRAMDATA").map(PathBuf::from);
+
+        dirs::config_dir().into_iter()
+            .chain(program_data)
+            .map(|dir| dir.join(&app).join(config_file_name))
+            .collect()
+    }
+
+    /// Picks the first candidate that could be created, i.e. whose closest existing ancestor is a writable directory.
+    pub fn recommended_location<T: AsRef<Path>>(candidates: &[T]) -> Option<PathBuf> {
+        candidates.iter()
+            .map(AsRef::as_ref)
+            .find(|candidate| is_creatable(candidate))
+            .map(Path::to_path_buf)
+    }
+
+    fn is_creatable(path: &Path) -> bool {
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        match dir.ancestors().find(|ancestor| ancestor.exists()) {
+            Some(existing) => existing.is_dir() && is_writable_dir(existing),
+            None => false,
+        }
+    }
+
+    #[cfg(unix)]
+    fn is_writable_dir(dir: &Path) -> bool {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        match CString::new(dir.as_os_str().as_bytes()) {
+            Ok(dir) => unsafe { libc::access(dir.as_ptr(), libc::W_OK) == 0 },
+            Err(_) => false,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn is_writable_dir(dir: &Path) -> bool {
+        fs::metadata(dir).map(|metadata| !metadata.permissions().readonly()).unwrap_or(false)
+    }
+
+    /// Builds `NoSuitableConfigFoundCreateAt` if one of `configs` can be created, else `NoSuitableConfigFound`.
+    fn no_suitable_config(configs: Vec<String>) -> ConfigError {
+        match recommended_location(&configs) {
+            Some(location) => ConfigErrorKind::NoSuitableConfigFoundCreateAt(configs, location.to_string_lossy().to_string()).into(),
+            None => ConfigErrorKind::NoSuitableConfigFound(configs).into(),
+        }
+    }
+
+    impl ConfigError {
+        /// Adds the recommended location to a `NoSuitableConfigFound`, e.g. from a derived `smart_load`; other errors
+        /// are returned unchanged.
+        pub fn with_creation_hint(self) -> Self {
+            match self.kind() {
+                ConfigErrorKind::NoSuitableConfigFound(configs) => no_suitable_config(configs.clone()),
+                _ => self,
+            }
+        }
+    }
+
+    /// Fails if the file is accessible by group or others, like ssh does for private keys. No-op on non-Unix platforms.
+    pub fn check_permissions<T: AsRef<Path>>(file_path: T) -> ConfigResult<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let file_path = file_path.as_ref();
+            let mode = fs::metadata(file_path)?.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                return Err(ConfigErrorKind::InsecurePermissions(file_path.to_string_lossy().to_string(), mode).into());
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = file_path;
+
+        Ok(())
+    }
+
+    /// Validates a config struct or a raw `toml::Value` against an external JSON Schema file.
+    #[cfg(feature = "schema")]
+    pub fn validate_against_schema<T: Serialize, P: AsRef<Path>>(value: &T, schema_path: P) -> ConfigResult<()> {
+        let schema_path = schema_path.as_ref();
+        let invalid_schema = |reason: String| ConfigErrorKind::InvalidSchema(schema_path.to_string_lossy().to_string(), reason);
+
+        let schema: serde_json::Value = serde_json::from_str(&fs::read_to_string(schema_path)?)
+            .map_err(|e| invalid_schema(e.to_string()))?;
+        let validator = jsonschema::validator_for(&schema).map_err(|e| invalid_schema(e.to_string()))?;
+        let instance = serde_json::to_value(value)
+            .map_err(|e| ConfigErrorKind::SchemaViolations(vec![format!("/: {}", e)]))?;
+
+        let violations: Vec<String> = validator.iter_errors(&instance)
+            .map(|e| {
+                let path = e.instance_path.to_string();
+                format!("{}: {}", if path.is_empty() { "/" } else { &path }, e)
+            })
+            .collect();
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrorKind::SchemaViolations(violations).into())
+        }
+    }
+
+    /// Substitutes `${VAR}` and `${VAR:-default}` with environment variables; `$$` yields a literal `$`.
+    pub fn render_template<T: AsRef<Path>, U: AsRef<Path>>(template_path: T, out_path: U) -> ConfigResult<()> {
+        let template = fs::read_to_string(template_path)?;
+        let rendered = render(&template, |name| env::var(name).ok())?;
+        ensure_parent_dir(&out_path)?;
+        fs::write(out_path, rendered)?;
+
+        Ok(())
+    }
+
+    fn render<F: Fn(&str) -> Option<String>>(template: &str, lookup: F) -> ConfigResult<String> {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(pos) = rest.find('$') {
+            rendered.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+            if let Some(stripped) = rest.strip_prefix('$') {
+                rendered.push('$');
+                rest = stripped;
+            } else if let Some(stripped) = rest.strip_prefix('{') {
+                let end = stripped.find('}')
+                    .ok_or_else(|| ConfigErrorKind::InvalidTemplate(format!("unterminated placeholder '${{{}'", stripped)))?;
+                let placeholder = &stripped[..end];
+                let (name, default) = match placeholder.find(":-") {
+                    Some(sep) => (&placeholder[..sep], Some(&placeholder[sep + 2..])),
+                    None => (placeholder, None),
+                };
+                if name.is_empty() {
+                    return Err(ConfigErrorKind::InvalidTemplate(format!("empty placeholder '${{{}}}'", placeholder)).into());
+                }
+                let value = match (lookup(name), default) {
+                    (Some(ref value), Some(default)) if value.is_empty() => default.to_owned(),
+                    (Some(value), _) => value,
+                    (None, Some(default)) => default.to_owned(),
+                    (None, None) => return Err(ConfigErrorKind::UnsetVariable(name.to_owned()).into()),
+                };
+                rendered.push_str(&value);
+                rest = &stripped[end + 1..];
+            } else {
+                rendered.push('$');
+            }
+        }
+        rendered.push_str(rest);
+
+        Ok(rendered)
+    }
+
+    error_chain! {
+        types {
+            ConfigError, ConfigErrorKind, ConfigResultExt, ConfigResult;
+        }
+
+        errors {
+            NoSuitableConfigFound(configs: Vec<String>) {
+                description("No suitable configuration found")
+                display("No suitable configuration found '{:?}'", configs)
+            }
+            NoSuitableConfigFoundCreateAt(configs: Vec<String>, location: String) {
+                description("No suitable configuration found")
+                display("No suitable configuration found '{:?}'; create one at '{}'", configs, location)
+            }
+            NoSuchField(path: String) {
+                description("No such configuration field")
+                display("No such configuration field '{}'", path)
+            }
+            UnsetVariable(name: String) {
+                description("Environment variable required by template is not set")
+                display("Environment variable '{}' required by template is not set", name)
+            }
+            InvalidTemplate(reason: String) {
+                description("Invalid configuration template")
+                display("Invalid configuration template: {}", reason)
+            }
+            InsecurePermissions(path: String, mode: u32) {
+                description("Configuration file is accessible by group or others")
+                display("Configuration file '{}' has mode {:o} and is accessible by group or others; run 'chmod 600 {}'", path, mode, path)
+            }
+            UnknownFields(paths: Vec<String>) {
+                description("Configuration contains unknown fields")
+                display("Configuration contains unknown fields: {}", paths.join(", "))
+            }
+            Warnings(messages: Vec<String>) {
+                description("Configuration has warnings")
+                display("Configuration has warnings: {}", messages.join("; "))
+            }
+            AmbiguousConfig(configs: Vec<String>) {
+                description("More than one configuration found")
+                display("More than one configuration found, remove all but one of '{:?}'", configs)
+            }
+            UnsupportedFormat(extension: String) {
+                description("Unsupported configuration format")
+                display("Configuration format '{}' is not supported for this operation", extension)
+            }
+            TooLarge(max_bytes: usize) {
+                description("Configuration exceeds size limit")
+                display("Configuration exceeds size limit of {} bytes", max_bytes)
+            }
+            InvalidPriority(path: String) {
+                description("Configuration fragment has a non-integer priority")
+                display("Configuration fragment '{}' has a non-integer priority", path)
+            }
+            InvalidSchema(path: String, reason: String) {
+                description("Invalid JSON Schema")
+                display("Invalid JSON Schema '{}': {}", path, reason)
+            }
+            SchemaViolations(violations: Vec<String>) {
+                description("Configuration violates schema")
+                display("Configuration violates schema: {}", violations.join("; "))
+            }
+            InvalidKey(source: String, reason: String) {
+                description("Invalid encryption key")
+                display("Invalid encryption key from {}: {}", source, reason)
+            }
+            DecryptionFailed(reason: String) {
+                description("Failed to decrypt configuration")
+                display("Failed to decrypt configuration: {}", reason)
+            }
+            EncryptionFailed(reason: String) {
+                description("Failed to encrypt configuration")
+                display("Failed to encrypt configuration: {}", reason)
+            }
+            SecretUnavailable(reference: String, reason: String) {
+                description("Could not resolve secret reference")
+                display("Could not resolve secret reference '{}': {}", reference, reason)
+            }
+            InvalidConfig(path: String, reason: String) {
+                description("Configuration is invalid")
+                display("Configuration '{}' is invalid: {}", path, reason)
+            }
+            EnvVarNotSet(name: String) {
+                description("Environment variable is not set")
+                display("Environment variable '{}' is not set", name)
+            }
+            InvalidBackup(reason: String) {
+                description("Backup policy is invalid")
+                display("Backup policy is invalid: {}", reason)
+            }
+            InvalidEnvVar(name: String, reason: String) {
+                description("Environment variable has invalid content")
+                display("Environment variable '{}' has invalid content: {}", name, reason)
+            }
+            FetchFailed(url: String, reason: String) {
+                description("Failed to fetch configuration")
+                display("Failed to fetch configuration from '{}': {}", url, reason)
+            }
+        }
+
+        foreign_links {
+            CouldNotRead(::std::io::Error);
+            CouldNotParse(::toml::de::Error);
+            CouldNotWrite(::toml::ser::Error);
+            CouldNotParseYaml(::serde_yaml::Error);
+            CouldNotParseJson(::serde_json::Error);
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::test_util::test_dir;
+        use clams_derive::Config;
+        use serde::{Deserialize, Serialize};
+        use spectral::prelude::*;
+
+        #[derive(Config, Debug, Default, Serialize, Deserialize, PartialEq)]
+        struct MyConfig {
+            pub general: General,
+        }
+
+        #[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+        struct General {
+            pub name: String,
+        }
+
+        mod service_config {
+            use super::*;
+
+            #[derive(Config, Debug, Serialize, Deserialize, PartialEq)]
+            pub struct ServiceConfig {
+                pub general: General,
+                pub server: Server,
+            }
+
+            impl Default for ServiceConfig {
+                fn default() -> Self {
+                    ServiceConfig {
+                        general: General { name: "service".to_owned() },
+                        server: Server { host: "localhost".to_owned(), port: 8080, user: None },
+                    }
+                }
+            }
+
+            #[derive(Debug, Serialize, Deserialize, PartialEq)]
+            pub struct Server {
+                pub host: String,
+                pub port: u16,
+                pub user: Option<String>,
+            }
+
+            pub fn changed_service_config() -> ServiceConfig {
+                ServiceConfig {
+                    general: General { name: "changed".to_owned() },
+                    server: Server { host: "example.com".to_owned(), port: 9090, user: Some("admin".to_owned()) },
+                }
+            }
+        }
+        use self::service_config::*;
+
+        mod typed_config {
+            use super::*;
+            use chrono::{DateTime, TimeZone, Utc};
+
+            #[derive(Config, Debug, Serialize, Deserialize, PartialEq)]
+            pub struct TypedConfig {
+                #[serde(with = "crate::config::toml_datetime")]
+                pub created: DateTime<Utc>,
+                pub mode: Mode,
+            }
+
+            #[derive(Debug, Serialize, Deserialize, PartialEq)]
+            #[serde(rename_all = "lowercase")]
+            pub enum Mode {
+                Fast,
+                Safe,
+            }
+
+            #[test]
+            fn datetime_and_enum_round_trip() {
+                let dir = test_dir("datetime_and_enum_round_trip");
+                let file_path = dir.join("typed.toml");
+                let config = TypedConfig {
+                    created: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+                    mode: Mode::Safe,
+                };
+
+                config.save(&file_path).expect("Could not save");
+                let content = fs::read_to_string(&file_path).expect("Could not read");
+                let res = TypedConfig::from_file(&file_path);
+
+                assert_that(&content).is_equal_to("created = 2024-01-01T00:00:00Z\nmode = 'safe'\n".to_owned());
+                assert_that(&res).is_ok().is_equal_to(config);
+            }
+
+            #[test]
+            fn datetime_from_string() {
+                let dir = test_dir("datetime_from_string");
+                let file_path = dir.join("typed.toml");
+                fs::write(&file_path, "created = \"2024-01-01T01:00:00+01:00\"\nmode = \"fast\"\n").expect("Could not write");
+
+                let res = TypedConfig::from_file(&file_path);
+
+                assert_that(&res).is_ok().map(|c| &c.created).is_equal_to(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+            }
+        }
+
+        mod merged_config {
+            use super::*;
+
+            #[derive(Config, Debug, Serialize, Deserialize, PartialEq)]
+            pub struct MergedConfig {
+                pub name: String,
+                pub user: Option<String>,
+                pub servers: Vec<String>,
+                pub server: Server,
+            }
+
+            fn config(name: &str, user: Option<&str>, servers: &[&str], port: u16) -> MergedConfig {
+                MergedConfig {
+                    name: name.to_owned(),
+                    user: user.map(ToOwned::to_owned),
+                    servers: servers.iter().map(|s| s.to_string()).collect(),
+                    server: Server { host: "localhost".to_owned(), port, user: None },
+                }
+            }
+
+            #[test]
+            fn merge_replaces_vecs() {
+                let mut base = config("base", Some("admin"), &["a", "b"], 8080);
+
+                let res = base.merge(config("other", None, &["c"], 9090), VecMerge::Replace);
+
+                assert_that(&res).is_ok();
+                assert_that(&base).is_equal_to(config("other", Some("admin"), &["c"], 9090));
+            }
+
+            #[test]
+            fn merge_appends_vecs() {
+                let mut base = config("base", None, &["a", "b"], 8080);
+
+                let res = base.merge(config("other", Some("root"), &["c"], 8080), VecMerge::Append);
+
+                assert_that(&res).is_ok();
+                assert_that(&base).is_equal_to(config("other", Some("root"), &["a", "b", "c"], 8080));
+            }
+        }
+
+        mod secret_config {
+            use super::*;
+
+            #[derive(Config, Debug, Serialize, Deserialize, PartialEq)]
+            pub struct SecretConfig {
+                #[serde(deserialize_with = "secret_ref::deserialize")]
+                pub api_key: String,
+                #[serde(deserialize_with = "secret_ref::deserialize")]
+                pub user: String,
+            }
+
+            #[test]
+            fn from_file_resolves_secret_refs() {
+                let dir = test_dir("from_file_resolves_secret_refs");
+                let secret_path = dir.join("api_key");
+                fs::write(&secret_path, "s3cr3t\n").expect("Could not write");
+                let file_path = dir.join("secret.toml");
+                fs::write(&file_path, format!("api_key = \"file:{}\"\nuser = \"admin\"\n", secret_path.display())).expect("Could not write");
+
+                let res = SecretConfig::from_file(&file_path);
+
+                assert_that(&res).is_ok().is_equal_to(SecretConfig { api_key: "s3cr3t".to_owned(), user: "admin".to_owned() });
+            }
+
+            #[test]
+            fn from_file_reports_unavailable_secret() {
+                let dir = test_dir("from_file_reports_unavailable_secret");
+                let file_path = dir.join("secret.toml");
+                fs::write(&file_path, "api_key = \"env:CLAMS_TEST_NO_SUCH_SECRET\"\nuser = \"admin\"\n").expect("Could not write");
+
+                let res = SecretConfig::from_file(&file_path).map_err(|e| e.kind().to_string());
+
+                match res {
+                    Err(msg) => assert_that(&msg.contains("env:CLAMS_TEST_NO_SUCH_SECRET")).is_true(),
+                    Ok(_) => panic!("Expected unavailable secret"),
+                }
+            }
+        }
+
+        mod flattened_config {
+            use super::*;
+
+            #[derive(Config, Debug, Serialize, Deserialize, PartialEq)]
+            pub struct FlattenedConfig {
+                pub server: Server,
+                #[serde(flatten)]
+                pub shared: Shared,
+            }
+
+            #[derive(Debug, Serialize, Deserialize, PartialEq)]
+            pub struct Shared {
+                pub log_level: String,
+                pub retries: u32,
+            }
+
+            impl Default for FlattenedConfig {
+                fn default() -> Self {
+                    FlattenedConfig {
+                        shared: Shared { log_level: "info".to_owned(), retries: 3 },
+                        server: Server { host: "localhost".to_owned(), port: 8080, user: None },
+                    }
+                }
+            }
+
+            #[test]
+            fn flatten_round_trip() {
+                let dir = test_dir("flatten_round_trip");
+                let file_path = dir.join("flattened.toml");
+                let config = FlattenedConfig::default();
+
+                let file = fs::File::create(&file_path).expect("Could not create");
+                config.save_to_writer(file, Format::Toml).expect("Could not save");
+                let res = FlattenedConfig::from_file(&file_path);
+
+                assert_that(&res).is_ok().is_equal_to(config);
+            }
+
+            #[test]
+            fn flatten_save_with_backup() {
+                let dir = test_dir("flatten_save_with_backup");
+                let file_path = dir.join("flattened.toml");
+                let mut config = FlattenedConfig::default();
+
+                let first = config.save_with_backup(&file_path, Backup::Rolling);
+                config.shared.retries = 5;
+                let second = config.save_with_backup(&file_path, Backup::Rolling);
+
+                assert_that(&first).is_ok().is_none();
+                assert_that(&second).is_ok().is_some();
+                assert_that(&FlattenedConfig::from_file(&file_path)).is_ok().is_equal_to(config);
+                assert_that(&FlattenedConfig::from_file(dir.join("flattened.toml.bak"))).is_ok().is_equal_to(FlattenedConfig::default());
+            }
+
+            #[test]
+            fn flatten_default_keys_at_top_level() {
+                let res = FlattenedConfig::default_config_string(None);
+
+                assert_that(&res).is_ok().is_equal_to("log_level = 'info'\nretries = 3\n\n[server]\nhost = 'localhost'\nport = 8080\n".to_owned());
+            }
+
+            #[test]
+            fn flatten_non_default_fields() {
+                let mut config = FlattenedConfig::default();
+                config.shared.retries = 5;
+                config.server.port = 9090;
+
+                let res = config.non_default_fields();
+
+                assert_that(&res).is_ok().is_equal_to(vec![
+                    ("retries".to_owned(), "5".to_owned()),
+                    ("server.port".to_owned(), "9090".to_owned()),
+                ]);
+            }
+        }
+
+        #[test]
+        fn from_file_okay() {
+            let my_config = MyConfig::from_file("examples/my_config.toml");
+
+            assert_that(&my_config).is_ok();
+        }
+
+        #[test]
+        fn from_file_detect_okay() {
+            let res = MyConfig::from_file_detect("examples/my_config.toml");
+
+            assert_that(&res).is_ok().map(|r| &r.1).is_equal_to(Format::Toml);
+        }
+
+        #[test]
+        fn yaml_round_trip() {
+            let dir = test_dir("yaml_round_trip");
+            let file_path = dir.join("service.yaml");
+            let config = ServiceConfig::default();
+            let file = fs::File::create(&file_path).expect("Could not create");
+            config.save_to_writer(file, Format::Yaml).expect("Could not save");
+
+            let res = ServiceConfig::from_yaml_file(&file_path);
+            let detected = ServiceConfig::from_file_detect(&file_path);
+            let layered = ServiceConfig::load_over(&file_path, changed_service_config());
+
+            assert_that(&fs::read_to_string(&file_path).expect("Could not read").contains("user: null")).is_true();
+            assert_that(&res).is_ok().is_equal_to(config);
+            assert_that(&detected).is_ok().map(|r| &r.1).is_equal_to(Format::Yaml);
+            assert_that(&layered).is_ok().map(|c| &c.server.host).is_equal_to("localhost".to_owned());
+            assert_that(&layered).is_ok().map(|c| &c.server.user).is_equal_to(Some("admin".to_owned()));
+        }
+
+        #[test]
+        fn smart_load_detect_mixed_formats() {
+            let dir = test_dir("smart_load_detect_mixed_formats");
+            let json_path = dir.join("service.json");
+            let config = changed_service_config();
+            let file = fs::File::create(&json_path).expect("Could not create");
+            config.save_to_writer(file, Format::Json).expect("Could not save");
+            let candidates = vec![dir.join("missing.toml"), json_path.clone(), PathBuf::from("examples/my_config.toml")];
+
+            let res = ServiceConfig::smart_load_detect(&candidates);
+
+            assert_that(&res).is_ok().map(|r| &r.1).is_equal_to(json_path.as_path());
+            assert_that(&res).is_ok().map(|r| &r.0).is_equal_to(config);
+            assert_that(&ServiceConfig::smart_load_detect(&candidates[..1])).is_err();
+        }
+
+        #[test]
+        fn format_from_path() {
+            assert_that(&Format::from_path("config.TOML")).is_equal_to(Format::Toml);
+            assert_that(&Format::from_path("config")).is_equal_to(Format::Toml);
+            assert_that(&Format::from_path("config.yml")).is_equal_to(Format::Yaml);
+            assert_that(&Format::from_path("config.Json")).is_equal_to(Format::Json);
+            assert_that(&Format::from_extension("ini")).is_none();
+        }
+
+        fn parse_key_values(content: &str) -> ConfigResult<Value> {
+            let mut general = toml::value::Table::new();
+            for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                let (key, value) = line.split_once(':').ok_or_else(|| ConfigErrorKind::InvalidTemplate(line.to_owned()))?;
+                general.insert(key.trim().to_owned(), Value::String(value.trim().to_owned()));
+            }
+            let mut root = toml::value::Table::new();
+            root.insert("general".to_owned(), Value::Table(general));
+
+            Ok(Value::Table(root))
+        }
+
+        #[test]
+        fn register_format_okay() {
+            let dir = test_dir("register_format_okay");
+            let file_path = dir.join("my_config.KV");
+            fs::write(&file_path, "name: custom\n").expect("Could not write");
+
+            register_format("kv", parse_key_values);
+            let res = MyConfig::from_file_detect(&file_path);
+
+            assert_that(&res).is_ok().map(|r| &r.1).is_equal_to(Format::Custom("kv"));
+            assert_that(&res).is_ok().map(|r| &r.0.general.name).is_equal_to("custom".to_owned());
+            assert_that(&MyConfig::default().save_to_writer(Vec::new(), Format::Custom("kv"))).is_err();
+        }
+
+        #[test]
+        fn format_from_content_type() {
+            assert_that(&Format::from_content_type("application/toml; charset=utf-8")).is_some().is_equal_to(Format::Toml);
+            assert_that(&Format::from_content_type("text/html")).is_none();
+        }
+
+        #[test]
+        fn smart_load_okay() {
+            let locations = vec!["tmp/my_config.toml", "tmp2/my_config.toml", "examples/my_config.toml"];
+
+            let res = MyConfig::smart_load(&locations);
+
+            assert_that(&res).is_ok();
+        }
+
+        #[test]
+        fn smart_load_faild() {
+            let locations = vec!["tmp/my_config.toml", "tmp2/my_config.toml"];
+
+            let res = MyConfig::smart_load(&locations);
+
+            assert_that(&res).is_err();
+        }
+
+        #[test]
+        fn recommended_location_okay() {
+            let dir = test_dir("recommended_location_okay");
+            let candidates = vec![
+                PathBuf::from("tests/data/file.exists/my_config.toml"),
+                dir.join("new/my_config.toml"),
+            ];
+
+            let res = recommended_location(&candidates);
+
+            assert_that(&res).is_some().is_equal_to(dir.join("new/my_config.toml"));
+        }
+
+        #[test]
+        fn no_suitable_config_suggests_location() {
+            let dir = test_dir("no_suitable_config_suggests_location");
+            let locations = vec![dir.join("my_config.toml")];
+
+            let res = MyConfig::smart_load(&locations).map_err(ConfigError::with_creation_hint);
+            let strict = MyConfig::smart_load_strict(&locations);
+
+            let expected = format!("; create one at '{}'", dir.join("my_config.toml").display());
+            for message in vec![res.err(), strict.err()].into_iter().map(|e| e.map(|e| e.to_string()).unwrap_or_default()) {
+                assert_that(&message.ends_with(&expected)).is_true();
+            }
+        }
+
+        #[test]
+        #[cfg(not(windows))]
+        fn default_locations_okay() {
+            let home_dir = home_dir().expect("Could not retrieve username");
+            let mut home_config = home_dir;
+            home_config.push(".my_config.toml");
+            let expected: Vec<PathBuf> = vec![
+                home_config,
+                PathBuf::from("/etc/my_config.toml"),
+            ];
+
+            let res = default_locations("my_config.toml");
+
+            assert_that(&res).is_equal_to(expected);
+        }
+
+        #[test]
+        #[cfg(windows)]
+        fn default_locations_okay() {
+            let expected: Vec<PathBuf> = vec![
+                dirs::config_dir().expect("Could not retrieve APPDATA").join("my_config").join("my_config.toml"),
+                PathBuf::from(env::var_os("PROGRAMDATA").expect("Could not retrieve PROGRAMDATA")).join("my_config").join("my_config.toml"),
+            ];
+
+            let res = default_locations("my_config.toml");
+
+            assert_that(&res).is_equal_to(expected);
+        }
+
+        #[test]
+        fn smart_load_from_default_locations_and_local() {
+            let mut locations = default_locations("my_config.toml");
+            locations.push(PathBuf::from("examples/my_config.toml"));
+
+            let res = MyConfig::smart_load(&locations);
+
+            assert_that(&res).is_ok();
+        }
+
+        #[test]
+        fn smart_load_strict_single() {
+            let dir = test_dir("smart_load_strict_single");
+            let locations = vec![dir.join("missing.toml"), PathBuf::from("examples/my_config.toml")];
+
+            let res = MyConfig::smart_load_strict(&locations);
+
+            assert_that(&res).is_ok().map(|(_, path)| path).is_equal_to(Path::new("examples/my_config.toml"));
+        }
+
+        #[test]
+        fn smart_load_strict_ambiguous() {
+            let dir = test_dir("smart_load_strict_ambiguous");
+            MyConfig::default().save(dir.join("my_config.toml")).expect("Could not save");
+            let locations = vec![dir.join("my_config.toml"), PathBuf::from("examples/my_config.toml")];
+
+            let res = MyConfig::smart_load_strict(&locations);
+
+            match res.map_err(|e| e.kind().to_string()) {
+                Err(message) => assert_that(&message.contains("examples/my_config.toml")).is_true(),
+                Ok(_) => panic!("Expected AmbiguousConfig"),
+            }
+        }
+
+        #[test]
+        fn from_reader_okay() {
+            let res = MyConfig::from_reader("[general]\nname = \"reader\"\n".as_bytes(), Format::Toml);
+
+            assert_that(&res).is_ok().map(|c| &c.general.name).is_equal_to("reader".to_owned());
+        }
+
+        #[test]
+        fn from_reader_limited_too_large() {
+            let content = "[general]\nname = \"reader\"\n";
+
+            let exact = MyConfig::from_reader_limited(content.as_bytes(), Format::Toml, content.len());
+            let too_large = MyConfig::from_reader_limited(content.as_bytes(), Format::Toml, content.len() - 1);
+
+            assert_that(&exact).is_ok();
+            match too_large.map_err(|e| e.kind().to_string()) {
+                Err(message) => assert_that(&message.as_str()).is_equal_to("Configuration exceeds size limit of 25 bytes"),
+                Ok(_) => panic!("Expected TooLarge"),
+            }
+        }
+
+        #[test]
+        fn save_to_writer_okay() {
+            let mut out = Vec::new();
+
+            let res = ServiceConfig::default().save_to_writer(&mut out, Format::Toml);
+
+            assert_that(&res).is_ok();
+            let content = String::from_utf8(out).expect("Not UTF-8");
+            assert_that(&toml::from_str::<ServiceConfig>(&content)).is_ok().is_equal_to(ServiceConfig::default());
+        }
+
+        #[test]
+        fn save_with_backup_rolling() {
+            let dir = test_dir("save_with_backup_rolling");
+            let file_path = dir.join("my_config.toml");
+            let mut my_config = MyConfig::default();
+            my_config.general.name = "first".to_owned();
+
+            let first = my_config.save_with_backup(&file_path, Backup::Rolling);
+            my_config.general.name = "second".to_owned();
+            let second = my_config.save_with_backup(&file_path, Backup::Rolling);
+
+            assert_that(&first).is_ok().is_none();
+            assert_that(&second).is_ok().is_some().is_equal_to(dir.join("my_config.toml.bak"));
+            let backup = MyConfig::from_file(dir.join("my_config.toml.bak")).expect("Could not load backup");
+            assert_that(&backup.general.name.as_str()).is_equal_to("first");
+        }
+
+        #[test]
+        fn save_with_backup_timestamped_prunes() {
+            let dir = test_dir("save_with_backup_timestamped_prunes");
+            let file_path = dir.join("my_config.toml");
+            for old in &["2000-01-01T00-00-00", "2000-01-02T00-00-00", "2000-01-03T00-00-00"] {
+                fs::write(dir.join(format!("my_config.toml.bak.{}", old)), "").expect("Could not write old backup");
+            }
+            MyConfig::default().save(&file_path).expect("Could not save");
+
+            let res = MyConfig::default().save_with_backup(&file_path, Backup::Timestamped { keep: 2 });
+
+            assert_that(&res).is_ok().is_some();
+            let mut remaining: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+            remaining.sort();
+            assert_that(&remaining.len()).is_equal_to(3);
+            assert_that(&remaining[1].to_string_lossy().as_ref()).is_equal_to("my_config.toml.bak.2000-01-03T00-00-00");
+        }
+
+        #[test]
+        fn save_with_backup_timestamped_does_not_collide() {
+            let dir = test_dir("save_with_backup_timestamped_does_not_collide");
+            let file_path = dir.join("my_config.toml");
+            MyConfig::default().save(&file_path).expect("Could not save");
+
+            let backups: Vec<_> = (0..3)
+                .map(|_| MyConfig::default().save_with_backup(&file_path, Backup::Timestamped { keep: 5 }).expect("Could not save"))
+                .collect();
+
+            assert_that(&backups.iter().collect::<BTreeSet<_>>().len()).is_equal_to(3);
+            for backup in backups {
+                assert_that(&backup.expect("No backup").exists()).is_true();
+            }
+        }
+
+        #[test]
+        fn backup_order_is_numeric() {
+            let mut suffixes = vec!["2000-01-01T00-00-00.000_10", "2000-01-01T00-00-00.000_2", "2000-01-01T00-00-00.000", "1999-12-31T23-59-59"];
+            suffixes.sort_by_key(|suffix| backup_order(suffix));
+
+            assert_that(&suffixes).is_equal_to(vec![
+                "1999-12-31T23-59-59", "2000-01-01T00-00-00.000", "2000-01-01T00-00-00.000_2", "2000-01-01T00-00-00.000_10",
+            ]);
+        }
+
+        #[test]
+        fn save_with_backup_yaml() {
+            let dir = test_dir("save_with_backup_yaml");
+            let file_path = dir.join("service.yaml");
+            let mut config = ServiceConfig::default();
+
+            let first = config.save_with_backup(&file_path, Backup::Rolling);
+            config.server.port = 9090;
+            let second = config.save_with_backup(&file_path, Backup::Rolling);
+
+            assert_that(&first).is_ok().is_none();
+            assert_that(&second).is_ok().is_some();
+            assert_that(&ServiceConfig::load_or_create(&file_path)).is_ok().is_equal_to(config);
+            assert_that(&fs::read_to_string(dir.join("service.yaml.bak")).expect("Could not read").starts_with("general:\n")).is_true();
+        }
+
+        #[test]
+        fn save_with_backup_rejects_keep_zero() {
+            let dir = test_dir("save_with_backup_rejects_keep_zero");
+            let file_path = dir.join("my_config.toml");
+            MyConfig::default().save(&file_path).expect("Could not save");
+
+            let res = MyConfig::default().save_with_backup(&file_path, Backup::Timestamped { keep: 0 }).map_err(|e| e.kind().to_string());
+
+            match res {
+                Err(message) => assert_that(&message.as_str()).is_equal_to("Backup policy is invalid: keep must be at least 1"),
+                Ok(_) => panic!("Expected InvalidBackup"),
+            }
+            assert_that(&fs::read_dir(&dir).unwrap().count()).is_equal_to(1);
+        }
+
+        #[test]
+        fn default_config_string_layout() {
+            let res = ServiceConfig::default_config_string(Some("Service configuration\n\nSee the manual."));
+
+            assert_that(&res).is_ok().is_equal_to(
+                "# Service configuration\n#\n# See the manual.\n\n\
+                 [general]\nname = 'service'\n\n\
+                 [server]\nhost = 'localhost'\nport = 8080\n".to_owned()
+            );
+        }
+
+        #[test]
+        fn write_default_okay() {
+            let dir = test_dir("write_default_okay");
+            let file_path = dir.join("service.toml");
+
+            ServiceConfig::write_default(&file_path, None).expect("Could not write default");
+            let res = ServiceConfig::from_file(&file_path);
+
+            assert_that(&res).is_ok().is_equal_to(ServiceConfig::default());
+        }
+
+        #[test]
+        fn non_default_fields_okay() {
+            let mut config = ServiceConfig::default();
+            config.server.port = 9090;
+            config.server.user = Some("admin".to_owned());
+
+            let res = config.non_default_fields();
+
+            assert_that(&res).is_ok().is_equal_to(vec![
+                ("server.port".to_owned(), "9090".to_owned()),
+                ("server.user".to_owned(), "\"admin\"".to_owned()),
+            ]);
+        }
+
+        #[test]
+        fn to_minimal_string_okay() {
+            let mut config = ServiceConfig::default();
+            config.server.port = 9090;
+
+            let res = config.to_minimal_string();
+
+            assert_that(&res).is_ok().is_equal_to("[server]\nport = 9090\n".to_owned());
+        }
+
+        #[test]
+        fn to_minimal_string_all_default() {
+            let res = ServiceConfig::default().to_minimal_string();
+
+            assert_that(&res).is_ok().is_equal_to(String::new());
+        }
+
+        #[test]
+        fn non_default_fields_none() {
+            let res = ServiceConfig::default().non_default_fields();
+
+            assert_that(&res).is_ok().is_empty();
+        }
+
+        #[test]
+        fn check_file_okay() {
+            let res = MyConfig::check_file("examples/my_config.toml");
+
+            assert_that(&res).is_ok();
+        }
+
+        #[test]
+        fn check_file_unknown_fields() {
+            let dir = test_dir("check_file_unknown_fields");
+            let file_path = dir.join("service.toml");
+            fs::write(&file_path, "verbose = true\n[general]\nname = \"service\"\n[server]\nhost = \"localhost\"\nport = 8080\nprot = 1\n").expect("Could not write");
+
+            let res = ServiceConfig::check_file(&file_path);
+
+            match res.map_err(|e| e.kind().to_string()) {
+                Err(message) => assert_that(&message.as_str()).is_equal_to("Configuration contains unknown fields: server.prot, verbose"),
+                Ok(_) => panic!("Expected UnknownFields"),
+            }
+        }
+
+        #[test]
+        fn check_file_type_mismatch() {
+            let dir = test_dir("check_file_type_mismatch");
+            let file_path = dir.join("service.toml");
+            fs::write(&file_path, "[general]\nname = \"service\"\n[server]\nhost = \"localhost\"\nport = \"http\"\n").expect("Could not write");
+
+            let res = ServiceConfig::check_file(&file_path);
+
+            assert_that(&res).is_err();
+        }
+
+        #[test]
+        fn save_diff_okay() {
+            let dir = test_dir("save_diff_okay");
+            let file_path = dir.join("service.toml");
+            fs::write(&file_path, "[general]\nname = \"service\"\n[server]\nhost = \"localhost\"\nport = 8080\nlegacy = true\n").expect("Could not write");
+            let mut config = ServiceConfig::default();
+            config.server.port = 9090;
+
+            let res = config.save_diff(&file_path);
+
+            assert_that(&res).is_ok().is_equal_to(vec![
+                FieldChange { path: "server.legacy".to_owned(), old: Some("true".to_owned()), new: None },
+                FieldChange { path: "server.port".to_owned(), old: Some("8080".to_owned()), new: Some("9090".to_owned()) },
+            ]);
+            let on_disk = fs::read_to_string(&file_path).expect("Could not read");
+            assert_that(&on_disk.contains("port = 8080")).is_true();
+        }
+
+        #[test]
+        fn from_env_var_plain_and_base64() {
+            let content = "[general]\nname = \"from env\"\n";
+            env::set_var("CLAMS_TEST_CONFIG_PLAIN", content);
+            env::set_var("CLAMS_TEST_CONFIG_BASE64", "W2dlbmVyYWxdCm5hbWUgPSAiZnJvbSBlbnYiCg==");
+            env::set_var("CLAMS_TEST_CONFIG_FLAGGED", "base64:W2dlbmVyYWxdCm5hbWUgPSAiZnJvbSBlbnYiCg==");
+
+            for var in &["CLAMS_TEST_CONFIG_PLAIN", "CLAMS_TEST_CONFIG_BASE64", "CLAMS_TEST_CONFIG_FLAGGED"] {
+                let res = MyConfig::from_env_var(var, Format::Toml);
+                assert_that(&res).is_ok().map(|c| &c.general.name).is_equal_to("from env".to_owned());
+            }
+        }
+
+        #[test]
+        fn from_env_var_errors() {
+            env::set_var("CLAMS_TEST_CONFIG_BROKEN", "base64:not base64!");
+
+            let unset = MyConfig::from_env_var("CLAMS_TEST_CONFIG_UNSET", Format::Toml).map_err(|e| e.kind().to_string());
+            let broken = MyConfig::from_env_var("CLAMS_TEST_CONFIG_BROKEN", Format::Toml).map_err(|e| e.kind().to_string());
+
+            match (unset, broken) {
+                (Err(unset), Err(broken)) => {
+                    assert_that(&unset.as_str()).is_equal_to("Environment variable 'CLAMS_TEST_CONFIG_UNSET' is not set");
+                    assert_that(&broken.starts_with("Environment variable 'CLAMS_TEST_CONFIG_BROKEN' has invalid content")).is_true();
+                }
+                _ => panic!("Expected errors"),
+            }
+        }
+
+        #[test]
+        fn reload_okay() {
+            let dir = test_dir("reload_okay");
+            let file_path = dir.join("service.toml");
+            let mut config = ServiceConfig::default();
+            let mut changed = ServiceConfig::default();
+            changed.server.port = 9090;
+            changed.save(&file_path).expect("Could not save");
+
+            let res = config.reload(&file_path);
+
+            assert_that(&res).is_ok().is_equal_to(vec![
+                FieldChange { path: "server.port".to_owned(), old: Some("8080".to_owned()), new: Some("9090".to_owned()) },
+            ]);
+            assert_that(&config).is_equal_to(changed);
+        }
+
+        #[test]
+        fn reload_keeps_config_on_error() {
+            let dir = test_dir("reload_keeps_config_on_error");
+            let file_path = dir.join("service.toml");
+            fs::write(&file_path, "[server\nport = ").expect("Could not write");
+            let mut config = changed_service_config();
+
+            let res = config.reload(&file_path);
+
+            assert_that(&res).is_err();
+            assert_that(&config).is_equal_to(changed_service_config());
+        }
+
+        #[test]
+        fn reload_validated_keeps_config_when_invalid() {
+            let dir = test_dir("reload_validated_keeps_config_when_invalid");
+            let file_path = dir.join("service.toml");
+            let mut invalid = ServiceConfig::default();
+            invalid.server.port = 0;
+            invalid.save(&file_path).expect("Could not save");
+            let mut config = changed_service_config();
+            let validate = |config: &ServiceConfig| if config.server.port == 0 { Err("port must not be 0".to_owned()) } else { Ok(()) };
+
+            let res = config.reload_validated(&file_path, validate);
+
+            match res.map_err(|e| e.kind().to_string()) {
+                Err(message) => assert_that(&message.ends_with("is invalid: port must not be 0")).is_true(),
+                Ok(_) => panic!("Expected InvalidConfig"),
+            }
+            assert_that(&config).is_equal_to(changed_service_config());
+
+            ServiceConfig::default().save(&file_path).expect("Could not save");
+            assert_that(&config.reload_validated(&file_path, validate)).is_ok();
+            assert_that(&config).is_equal_to(ServiceConfig::default());
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn load_checked_strict() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let dir = test_dir("load_checked_strict");
+            let file_path = dir.join("service.toml");
+            fs::write(&file_path, "verbose = true\n[general]\nname = \"service\"\n[server]\nhost = \"localhost\"\nport = 8080\n").expect("Could not write");
+            fs::set_permissions(&file_path, fs::Permissions::from_mode(0o600)).expect("Could not chmod");
+
+            let lenient = ServiceConfig::load_checked(&file_path, false);
+            let strict = ServiceConfig::load_checked(&file_path, true);
+
+            assert_that(&lenient).is_ok().is_equal_to(ServiceConfig::default());
+            match strict.map_err(|e| e.kind().to_string()) {
+                Err(message) => assert_that(&message.starts_with("Configuration has warnings: unknown field 'verbose'")).is_true(),
+                Ok(_) => panic!("Expected Warnings"),
+            }
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn load_checked_strict_clean() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let dir = test_dir("load_checked_strict_clean");
+            let file_path = dir.join("service.toml");
+            ServiceConfig::default().save(&file_path).expect("Could not save");
+            fs::set_permissions(&file_path, fs::Permissions::from_mode(0o600)).expect("Could not chmod");
+
+            let res = ServiceConfig::load_checked(&file_path, true);
+
+            assert_that(&res).is_ok().is_equal_to(ServiceConfig::default());
+        }
+
+        #[test]
+        fn load_or_create_creates_defaults() {
+            let dir = test_dir("load_or_create_creates_defaults");
+            let file_path = dir.join("nested/service.toml");
+
+            let created = ServiceConfig::load_or_create(&file_path);
+            let loaded = ServiceConfig::from_file(&file_path);
+
+            assert_that(&created).is_ok().is_equal_to(ServiceConfig::default());
+            assert_that(&loaded).is_ok().is_equal_to(ServiceConfig::default());
+        }
+
+        #[test]
+        fn load_or_create_writes_extension_format() {
+            let dir = test_dir("load_or_create_writes_extension_format");
+            let file_path = dir.join("service.yaml");
+
+            let created = ServiceConfig::load_or_create(&file_path);
+            let loaded = ServiceConfig::load_or_create(&file_path);
+
+            assert_that(&created).is_ok().is_equal_to(ServiceConfig::default());
+            assert_that(&loaded).is_ok().is_equal_to(ServiceConfig::default());
+            assert_that(&fs::read_to_string(&file_path).expect("Could not read").starts_with("general:\n")).is_true();
+            assert_that(&fs::read_dir(&dir).unwrap().count()).is_equal_to(1);
+        }
+
+        #[test]
+        fn load_or_create_keeps_invalid_file() {
+            let dir = test_dir("load_or_create_keeps_invalid_file");
+            let file_path = dir.join("service.toml");
+            fs::write(&file_path, "[server\n").expect("Could not write");
+
+            let res = ServiceConfig::load_or_create(&file_path);
+
+            assert_that(&res).is_err();
+            assert_that(&fs::read_to_string(&file_path).expect("Could not read")).is_equal_to("[server\n".to_owned());
+        }
+
+        #[cfg(feature = "remote")]
+        mod from_url {
+            use super::*;
+            use std::io::{BufRead, BufReader};
+            use std::net::TcpListener;
+            use std::thread;
+
+            /// Serves a single HTTP response with `content_type` and `body`, returning the URL to fetch.
+            fn serve_once(path: &str, content_type: &'static str, body: &'static str) -> String {
+                let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind");
+                let url = format!("http://{}/{}", listener.local_addr().expect("No local address"), path);
+                thread::spawn(move || {
+                    let (mut stream, _) = listener.accept().expect("Could not accept");
+                    let mut reader = BufReader::new(stream.try_clone().expect("Could not clone"));
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).map(|n| n > 0).unwrap_or(false) && line != "\r\n" {
+                        line.clear();
+                    }
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        content_type, body.len(), body);
+                    stream.write_all(response.as_bytes()).expect("Could not respond");
+                });
+                url
+            }
+
+            fn unreachable_url() -> String {
+                let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind");
+                format!("http://{}/service.json", listener.local_addr().expect("No local address"))
+            }
+
+            #[test]
+            fn unparsable_response_keeps_cache() {
+                let dir = test_dir("from_url_unparsable_response_keeps_cache");
+                let cache = dir.join("service.toml");
+                ServiceConfig::default().save(&cache).expect("Could not save");
+                let cached = fs::read_to_string(&cache).expect("Could not read");
+                let url = serve_once("service.toml", "text/html", "<html>Maintenance</html>");
+
+                let res = ServiceConfig::from_url(&url, Some(&cache));
+
+                assert_that(&res).is_err();
+                assert_that(&fs::read_to_string(&cache).expect("Could not read")).is_equal_to(cached);
+            }
+
+            #[test]
+            fn falls_back_to_converted_cache() {
+                let dir = test_dir("from_url_falls_back_to_converted_cache");
+                let cache = dir.join("service.cache");
+                let body = r#"{"general": {"name": "remote"}, "server": {"host": "remote.local", "port": 9090}}"#;
+                let url = serve_once("service", "application/json", body);
+
+                let fetched = ServiceConfig::from_url(&url, Some(&cache));
+                let fallback = ServiceConfig::from_url(&unreachable_url(), Some(&cache));
+
+                assert_that(&fetched).is_ok().map(|c| &c.server.host).is_equal_to("remote.local".to_owned());
+                assert_that(&fallback).is_ok().is_equal_to(fetched.unwrap());
+            }
+        }
+
+        #[cfg(feature = "encryption")]
+        #[test]
+        fn save_encrypted_round_trip() {
+            use age::secrecy::ExposeSecret;
+
+            let dir = test_dir("save_encrypted_round_trip");
+            let file_path = dir.join("service.toml.age");
+            let key_path = dir.join("key.txt");
+            let identity = age::x25519::Identity::generate().to_string();
+            fs::write(&key_path, format!("# created: today\n{}\n", identity.expose_secret())).expect("Could not write");
+            let key_source = KeySource::File(key_path);
+            let mut config = ServiceConfig::default();
+            config.server.host = "secret.local".to_owned();
+
+            assert_that(&config.save_encrypted(&file_path, &key_source)).is_ok();
+            let ciphertext = fs::read(&file_path).expect("Could not read");
+            assert_that(&String::from_utf8_lossy(&ciphertext).contains("secret.local")).is_false();
+
+            let res = ServiceConfig::from_file_encrypted(&file_path, &key_source);
+            assert_that(&res).is_ok().is_equal_to(config);
+
+            let other = age::x25519::Identity::generate().to_string();
+            fs::write(dir.join("other.txt"), other.expose_secret()).expect("Could not write");
+            let res = ServiceConfig::from_file_encrypted(&file_path, &KeySource::File(dir.join("other.txt")));
+            assert_that(&res).is_err();
+        }
+
+        #[test]
+        fn load_over_okay() {
+            let dir = test_dir("load_over_okay");
+            let file_path = dir.join("service.toml");
+            fs::write(&file_path, "[server]\nport = 9090\n").expect("Could not write");
+            let mut base = ServiceConfig::default();
+            base.server.host = "platform.local".to_owned();
+
+            let res = ServiceConfig::load_over(&file_path, base);
+
+            assert_that(&res).is_ok().map(|c| &c.server.port).is_equal_to(9090);
+            assert_that(&res).is_ok().map(|c| &c.server.host).is_equal_to("platform.local".to_owned());
+            assert_that(&res).is_ok().map(|c| &c.general.name).is_equal_to("service".to_owned());
+        }
+
+        #[test]
+        fn apply_env_overrides_okay() {
+            let mut value = Value::try_from(changed_service_config()).expect("Could not convert");
+            let vars = vec![
+                ("MYAPP_GENERAL_NAME".to_owned(), "from env".to_owned()),
+                ("MYAPP_SERVER_PORT".to_owned(), "7070".to_owned()),
+            ].into_iter().collect();
+
+            apply_env_overrides(&mut value, "MYAPP", &FieldTree::of::<ServiceConfig>(), &vars, false).expect("Could not apply");
+            let config: ServiceConfig = value.try_into().expect("Could not convert");
+
+            assert_that(&config.general.name.as_str()).is_equal_to("from env");
+            assert_that(&config.server.port).is_equal_to(7070);
+            assert_that(&config.server.host.as_str()).is_equal_to("example.com");
+        }
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct EnvConfig {
+            ratio: f64,
+            #[serde(rename = "maxRetries")]
+            max_retries: Option<u32>,
+            log: EnvLog,
+            metrics: Option<EnvMetrics>,
+        }
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct EnvLog {
+            level: String,
+            file: Option<String>,
+        }
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct EnvMetrics {
+            host: String,
+        }
+
+        #[test]
+        fn field_tree_okay() {
+            let leaf = || FieldTree::default();
+            let tree = |fields: Vec<(&str, FieldTree)>| FieldTree(fields.into_iter().map(|(name, tree)| (name.to_owned(), tree)).collect());
+
+            assert_that(&FieldTree::of::<EnvConfig>()).is_equal_to(tree(vec![
+                ("ratio", leaf()),
+                ("maxRetries", leaf()),
+                ("log", tree(vec![("level", leaf()), ("file", leaf())])),
+                ("metrics", tree(vec![("host", leaf())])),
+            ]));
+        }
+
+        #[test]
+        fn apply_env_overrides_coerces_and_adds_missing() {
+            let mut value: Value = toml::from_str("ratio = 0.5\n[log]\nlevel = 'info'\n").expect("Could not parse");
+            let vars = vec![
+                ("MYAPP_RATIO".to_owned(), "2".to_owned()),
+                ("MYAPP_LOG_LEVEL".to_owned(), "debug".to_owned()),
+                ("MYAPP_LOG_FILE".to_owned(), "app.log".to_owned()),
+                ("MYAPP_MAXRETRIES".to_owned(), "3".to_owned()),
+                ("MYAPP_METRICS_HOST".to_owned(), "metrics.local".to_owned()),
+                ("MYAPP_UNKNOWN".to_owned(), "ignored".to_owned()),
+            ].into_iter().collect();
+
+            apply_env_overrides(&mut value, "MYAPP", &FieldTree::of::<EnvConfig>(), &vars, false).expect("Could not apply");
+
+            assert_that(&value.get("unknown")).is_none();
+            let config: EnvConfig = value.try_into().expect("Could not convert");
+            assert_that(&config).is_equal_to(EnvConfig {
+                ratio: 2.0,
+                max_retries: Some(3),
+                log: EnvLog { level: "debug".to_owned(), file: Some("app.log".to_owned()) },
+                metrics: Some(EnvMetrics { host: "metrics.local".to_owned() }),
+            });
+        }
+
+        #[test]
+        fn from_file_with_env_sets_missing_options() {
+            let dir = test_dir("from_file_with_env_sets_missing_options");
+            let file_path = dir.join("service.toml");
+            ServiceConfig::default().save(&file_path).expect("Could not save");
+            env::set_var("CLAMS_TEST_MISSING_SERVER_USER", "1234");
+
+            let res = ServiceConfig::from_file_with_env(&file_path, "CLAMS_TEST_MISSING");
+
+            assert_that(&res).is_ok().map(|c| &c.server.user).is_equal_to(Some("1234".to_owned()));
+        }
+
+        #[test]
+        fn from_file_with_env_rejects_mistyped_values() {
+            let dir = test_dir("from_file_with_env_rejects_mistyped_values");
+            let file_path = dir.join("service.toml");
+            ServiceConfig::default().save(&file_path).expect("Could not save");
+            env::set_var("CLAMS_TEST_ENV_SERVER_HOST", "env.local");
+            env::set_var("CLAMS_TEST_BROKEN_SERVER_PORT", "eighty");
+
+            let res = ServiceConfig::from_file_with_env(&file_path, "clams_test_env");
+            let broken = ServiceConfig::from_file_with_env(&file_path, "CLAMS_TEST_BROKEN").map_err(|e| e.kind().to_string());
+
+            assert_that(&res).is_ok().map(|c| &c.server.host).is_equal_to("env.local".to_owned());
+            match broken {
+                Err(message) => assert_that(&message.starts_with("Environment variable 'CLAMS_TEST_BROKEN_SERVER_PORT'")).is_true(),
+                Ok(_) => panic!("Expected InvalidEnvVar"),
+            }
+        }
+
+        #[test]
+        fn smart_merge_layers_candidates() {
+            let dir = test_dir("smart_merge_layers_candidates");
+            let user_path = dir.join("user.toml");
+            let system_path = dir.join("system.toml");
+            fs::write(&user_path, "[server]\nport = 9090\n").expect("Could not write");
+            ServiceConfig::default().save(&system_path).expect("Could not save");
+            let candidates = vec![user_path.clone(), dir.join("missing.toml"), system_path.clone()];
+
+            let res = ServiceConfig::smart_merge(&candidates);
+
+            assert_that(&res).is_ok().map(|r| &r.1).is_equal_to(vec![user_path.as_path(), system_path.as_path()]);
+            assert_that(&res).is_ok().map(|r| &r.0.server.port).is_equal_to(9090);
+            assert_that(&res).is_ok().map(|r| &r.0.server.host).is_equal_to("localhost".to_owned());
+            assert_that(&ServiceConfig::smart_merge(&candidates[1..2])).is_err();
+        }
+
+        #[test]
+        fn env_overlay_path_okay() {
+            assert_that(&env_overlay_path(Path::new("/etc/app/config.toml"), "production"))
+                .is_equal_to(PathBuf::from("/etc/app/config.production.toml"));
+            assert_that(&env_overlay_path(Path::new("config"), "dev")).is_equal_to(PathBuf::from("config.dev"));
+        }
+
+        #[test]
+        fn load_for_env_okay() {
+            let dir = test_dir("load_for_env_okay");
+            let base_path = dir.join("service.toml");
+            ServiceConfig::default().save(&base_path).expect("Could not save");
+            fs::write(dir.join("service.production.toml"), "[server]\nhost = \"prod.example.com\"\n").expect("Could not write");
+
+            let production = ServiceConfig::load_for_env(&base_path, "production");
+            let staging = ServiceConfig::load_for_env(&base_path, "staging");
+
+            assert_that(&production).is_ok().map(|c| &c.server.host).is_equal_to("prod.example.com".to_owned());
+            assert_that(&production).is_ok().map(|c| &c.server.port).is_equal_to(8080);
+            assert_that(&staging).is_ok().is_equal_to(ServiceConfig::default());
+        }
+
+        #[test]
+        fn from_file_with_defaults_okay() {
+            let dir = test_dir("from_file_with_defaults_okay");
+            let defaults_path = dir.join("defaults.toml");
+            ServiceConfig::default().save(&defaults_path).expect("Could not save");
+            fs::write(dir.join("user.toml"), "[server]\nport = 9090\n").expect("Could not write");
+
+            let with_user = ServiceConfig::from_file_with_defaults(&defaults_path, dir.join("user.toml"));
+            let without_user = ServiceConfig::from_file_with_defaults(&defaults_path, dir.join("missing.toml"));
+
+            assert_that(&with_user).is_ok().map(|c| &c.server.port).is_equal_to(9090);
+            assert_that(&with_user).is_ok().map(|c| &c.server.host).is_equal_to("localhost".to_owned());
+            assert_that(&without_user).is_ok().is_equal_to(ServiceConfig::default());
+        }
+
+        #[test]
+        fn from_fragments_dir_orders_by_priority() {
+            let dir = test_dir("from_fragments_dir_orders_by_priority");
+            fs::write(dir.join("a.toml"), "priority = 90\n[server]\nport = 9090\n").expect("Could not write");
+            fs::write(dir.join("b.toml"), "priority = 10\n[server]\nport = 7070\nhost = \"b.local\"\n").expect("Could not write");
+            fs::write(dir.join("c.toml"), "[general]\nname = \"fragments\"\n[server]\nhost = \"c.local\"\nport = 1\n").expect("Could not write");
+            fs::write(dir.join("README"), "not a fragment").expect("Could not write");
+
+            let res = ServiceConfig::from_fragments_dir(&dir);
+
+            assert_that(&res).is_ok().map(|c| &c.server.port).is_equal_to(9090);
+            assert_that(&res).is_ok().map(|c| &c.server.host).is_equal_to("b.local".to_owned());
+            assert_that(&res).is_ok().map(|c| &c.general.name).is_equal_to("fragments".to_owned());
+        }
+
+        #[test]
+        fn from_fragments_dir_invalid_priority() {
+            let dir = test_dir("from_fragments_dir_invalid_priority");
+            fs::write(dir.join("a.toml"), "priority = \"high\"\n").expect("Could not write");
+
+            let res = ServiceConfig::from_fragments_dir(&dir);
+
+            match res.map_err(|e| e.kind().to_string()) {
+                Err(message) => assert_that(&message.contains("non-integer priority")).is_true(),
+                Ok(_) => panic!("Expected InvalidPriority"),
+            }
+        }
+
+        #[test]
+        fn reset_field_nested_only() {
+            let mut config = changed_service_config();
+
+            let res = config.reset_field("server.port");
+
+            assert_that(&res).is_ok();
+            assert_that(&config.server.port).is_equal_to(8080);
+            assert_that(&config.server.host.as_str()).is_equal_to("example.com");
+            assert_that(&config.general.name.as_str()).is_equal_to("changed");
+        }
+
+        #[test]
+        fn reset_field_to_none() {
+            let mut config = changed_service_config();
+
+            let res = config.reset_field("server.user");
+
+            assert_that(&res).is_ok();
+            assert_that(&config.server.user).is_none();
+        }
+
+        #[test]
+        fn reset_field_section_and_all() {
+            let mut config = changed_service_config();
+
+            config.reset_field("server").expect("Could not reset section");
+            assert_that(&config.server.host.as_str()).is_equal_to("localhost");
+            assert_that(&config.general.name.as_str()).is_equal_to("changed");
+
+            config.reset_field("").expect("Could not reset config");
+            assert_that(&config).is_equal_to(ServiceConfig::default());
+        }
+
+        #[test]
+        fn reset_field_unknown() {
+            let mut config = changed_service_config();
+
+            let res = config.reset_field("server.no_such_field");
+
+            assert_that(&res).is_err();
+        }
+
+        fn lookup(name: &str) -> Option<String> {
+            match name {
+                "NAME" => Some("my_config".to_owned()),
+                "EMPTY" => Some("".to_owned()),
+                _ => None,
+            }
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn check_permissions_okay() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let dir = test_dir("check_permissions_okay");
+            let file_path = dir.join("secret.toml");
+            fs::write(&file_path, "").expect("Could not write");
+
+            fs::set_permissions(&file_path, fs::Permissions::from_mode(0o600)).expect("Could not chmod");
+            assert_that(&check_permissions(&file_path)).is_ok();
+
+            fs::set_permissions(&file_path, fs::Permissions::from_mode(0o644)).expect("Could not chmod");
+            assert_that(&check_permissions(&file_path)).is_err();
+        }
+
+        #[cfg(feature = "schema")]
+        #[test]
+        fn validate_against_schema_reports_paths() {
+            let dir = test_dir("validate_against_schema_reports_paths");
+            let schema_path = dir.join("schema.json");
+            fs::write(&schema_path, r#"{
+                "type": "object",
+                "required": ["general"],
+                "properties": {
+                    "general": {
+                        "type": "object",
+                        "properties": { "port": { "type": "integer", "maximum": 65535 } }
+                    }
+                }
+            }"#).expect("Could not write");
+            let valid: toml::Value = toml::from_str("[general]\nport = 8080").expect("Could not parse");
+            let invalid: toml::Value = toml::from_str("[general]\nport = 99999").expect("Could not parse");
+
+            assert_that(&validate_against_schema(&valid, &schema_path)).is_ok();
+            match validate_against_schema(&invalid, &schema_path).map_err(|e| e.kind().to_string()) {
+                Err(msg) => assert_that(&msg.contains("/general/port: 99999")).is_true(),
+                Ok(_) => panic!("Expected schema violation"),
+            }
+        }
+
+        #[test]
+        fn resolve_secret_ref_env() {
+            env::set_var("CLAMS_TEST_SECRET_REF", "from env");
+
+            assert_that(&resolve_secret_ref("env:CLAMS_TEST_SECRET_REF")).is_ok().is_equal_to("from env".to_owned());
+            assert_that(&resolve_secret_ref("plain value")).is_ok().is_equal_to("plain value".to_owned());
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn resolve_secret_ref_cmd() {
+            assert_that(&resolve_secret_ref("cmd:echo from command")).is_ok().is_equal_to("from command".to_owned());
+            assert_that(&resolve_secret_ref("cmd:echo oops >&2; exit 3")).is_err();
+        }
+
+        #[test]
+        fn render_okay() {
+            let template = "name = \"${NAME}\"\nport = ${PORT:-8080}\nuser = \"${EMPTY:-nobody}\"\ncost = \"$$5 $ 3\"\n";
+
+            let res = render(template, lookup);
+
+            assert_that(&res).is_ok()
+                .is_equal_to("name = \"my_config\"\nport = 8080\nuser = \"nobody\"\ncost = \"$5 $ 3\"\n".to_owned());
+        }
+
+        #[test]
+        fn render_unset_variable() {
+            let res = render("name = \"${NO_SUCH_VAR}\"", lookup);
+
+            assert_that(&res).is_err();
+        }
+
+        #[test]
+        fn render_unterminated_placeholder() {
+            let res = render("name = \"${NAME\"", lookup);
+
+            assert_that(&res).is_err();
+        }
+
+        #[test]
+        fn render_template_okay() {
+            let dir = test_dir("render_template_okay");
+            let template_path = dir.join("my_config.toml.tmpl");
+            let out_path = dir.join("my_config.toml");
+            fs::write(&template_path, "[general]\nname = \"${CLAMS_TEST_UNSET_NAME:-from_template}\"\n").expect("Could not write template");
+
+            render_template(&template_path, &out_path).expect("Could not render template");
+            let res = MyConfig::from_file(&out_path);
+
+            assert_that(&res).is_ok().map(|c| &c.general.name).is_equal_to("from_template".to_owned());
+        }
+    }
+}
+
+pub mod console {
+    use crate::progress::{self, ProgressStyleExt};
+    use crate::theme;
+
+    use colored::{self, ColoredString, Colorize};
+    use indicatif::ProgressStyle;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::fmt;
+    use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use error_chain::*;
+
+    static INTERACTIVE: AtomicBool = AtomicBool::new(true);
+
+    thread_local! {
+        static SCRIPT: RefCell<Option<VecDeque<String>>> = const { RefCell::new(None) };
+    }
+
+    /// Feeds `answers`, one per line, to all following prompts on this thread instead of stdin until the returned
+    /// guard is dropped. Scripted prompts work in batch mode, ignore timeouts and see end of input once exhausted.
+    pub fn script_input<I: IntoIterator<Item = S>, S: Into<String>>(answers: I) -> ScriptedInput {
+        let answers = answers.into_iter().map(Into::into).collect();
+        SCRIPT.with(|script| *script.borrow_mut() = Some(answers));
+        ScriptedInput { _private: () }
+    }
+
+    #[must_use = "scripted input is removed when the guard is dropped"]
+    #[derive(Debug)]
+    pub struct ScriptedInput {
+        _private: (),
+    }
+
+    impl ScriptedInput {
+        /// Number of answers not yet consumed.
+        pub fn remaining(&self) -> usize {
+            SCRIPT.with(|script| script.borrow().as_ref().map(VecDeque::len).unwrap_or(0))
+        }
+    }
+
+    impl Drop for ScriptedInput {
+        fn drop(&mut self) {
+            SCRIPT.with(|script| *script.borrow_mut() = None);
+        }
+    }
+
+    fn is_scripted() -> bool {
+        SCRIPT.with(|script| script.borrow().is_some())
+    }
+
+    #[derive(Default)]
+    struct ScriptReader {
+        line: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Read for ScriptReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = {
+                let available = self.fill_buf()?;
+                let n = available.len().min(buf.len());
+                buf[..n].copy_from_slice(&available[..n]);
+                n
+            };
+            self.consume(n);
+            Ok(n)
+        }
+    }
+
+    impl BufRead for ScriptReader {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            if self.pos >= self.line.len() {
+                let next = SCRIPT.with(|script| script.borrow_mut().as_mut().and_then(VecDeque::pop_front));
+                self.line = next.map(|answer| format!("{}\n", answer).into_bytes()).unwrap_or_default();
+                self.pos = 0;
+            }
+            Ok(&self.line[self.pos..])
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.pos += amt;
+        }
+    }
+
+    /// Scripted input if installed on this thread, stdin otherwise.
+    fn input() -> Box<dyn BufRead> {
+        if is_scripted() {
+            Box::new(ScriptReader::default())
+        } else {
+            Box::new(BufReader::new(io::stdin()))
+        }
+    }
+
+    pub fn ask_for_confirmation(prompt: &str, expected: &str) -> Result<bool> {
+        if let Some(decision) = decide_unattended(prompt, None, true)? {
+            return Ok(decision);
+        }
+        let mut reader = input();
+        let mut writer = io::stdout();
+        ask_for_confirmation_from(&mut reader, &mut writer, prompt, expected)
+    }
+
+    pub fn ask_for_confirmation_from<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, prompt: &str, expected: &str) -> Result<bool> {
+        ask_for_confirmation_any_from(reader, writer, prompt, &[expected])
+    }
+
+    pub fn ask_for_confirmation_any(prompt: &str, accepted: &[&str]) -> Result<bool> {
+        if let Some(decision) = decide_unattended(prompt, None, true)? {
+            return Ok(decision);
+        }
+        let mut reader = input();
+        let mut writer = io::stdout();
+        ask_for_confirmation_any_from(&mut reader, &mut writer, prompt, accepted)
+    }
+
+    pub fn ask_for_confirmation_any_from<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, prompt: &str, accepted: &[&str]) -> Result<bool> {
+        let answer = read_answer(reader, writer, prompt)?.unwrap_or_default();
+        Ok(accepted.contains(&answer.trim()))
+    }
+
+    fn write_prompt<W: Write>(writer: &mut W, prompt: &str) -> Result<()> {
+        writer.write_all(prompt.as_bytes())
+            .chain_err(|| ErrorKind::FailedToReadConfirmation)?;
+        writer.flush()
+            .chain_err(|| ErrorKind::FailedToReadConfirmation)?;
+
+        Ok(())
+    }
+
+    fn read_answer<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, prompt: &str) -> Result<Option<String>> {
+        write_prompt(writer, prompt)?;
+
+        let mut input = String::new();
+        match reader.read_line(&mut input) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(input)),
+            Err(e) => Err(Error::with_chain(e, ErrorKind::FailedToReadConfirmation)),
+        }
+    }
+
+    /// Builder for confirmation prompts.
+    ///
+    /// By default only `yes` confirms. An empty answer yields the `default` if set; without a default it is
+    /// asked again up to `retries` times before counting as `false`. The `timeout` applies to `ask` only and
+    /// yields the default or `ErrorKind::Timeout`; the abandoned stdin read keeps running in the background.
+    #[derive(Clone, Debug)]
+    pub struct Confirm {
+        prompt: String,
+        accepted: Vec<String>,
+        default: Option<bool>,
+        case_insensitive: bool,
+        retries: usize,
+        timeout: Option<Duration>,
+    }
+
+    impl Confirm {
+        pub fn new(prompt: &str) -> Self {
+            Confirm {
+                prompt: prompt.to_owned(),
+                accepted: Vec::new(),
+                default: None,
+                case_insensitive: false,
+                retries: 0,
+                timeout: None,
+            }
+        }
+
+        pub fn accept(mut self, answer: &str) -> Self {
+            self.accepted.push(answer.to_owned());
+            self
+        }
+
+        pub fn default(mut self, default: bool) -> Self {
+            self.default = Some(default);
+            self
+        }
+
+        pub fn case_insensitive(mut self) -> Self {
+            self.case_insensitive = true;
+            self
+        }
+
+        pub fn retries(mut self, retries: usize) -> Self {
+            self.retries = retries;
+            self
+        }
+
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = Some(timeout);
+            self
+        }
+
+        pub fn ask(&self) -> Result<bool> {
+            if let Some(decision) = decide_unattended(&self.prompt, self.default, true)? {
+                return Ok(decision);
+            }
+            match self.timeout {
+                Some(timeout) if !is_scripted() => self.ask_with_timeout(timeout),
+                _ => {
+                    let mut reader = input();
+                    let mut writer = io::stdout();
+                    self.ask_from(&mut reader, &mut writer)
+                }
+            }
+        }
+
+        pub fn ask_from<R: BufRead, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<bool> {
+            for _ in 0..=self.retries {
+                let answer = read_answer(reader, writer, &self.prompt)?;
+                if let Some(confirmed) = self.evaluate(answer.as_deref()) {
+                    return Ok(confirmed);
+                }
+            }
+
+            Ok(false)
+        }
+
+        /// Like `ask`, but also returns the time from displaying the prompt until the answer has been read.
+        pub fn ask_timed(&self) -> Result<(bool, Duration)> {
+            let start = Instant::now();
+            self.ask().map(|confirmed| (confirmed, start.elapsed()))
+        }
+
+        pub fn ask_timed_from<R: BufRead, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<(bool, Duration)> {
+            let start = Instant::now();
+            self.ask_from(reader, writer).map(|confirmed| (confirmed, start.elapsed()))
+        }
+
+        fn ask_with_timeout(&self, timeout: Duration) -> Result<bool> {
+            let mut writer = io::stdout();
+            for _ in 0..=self.retries {
+                write_prompt(&mut writer, &self.prompt)?;
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || {
+                    let mut input = String::new();
+                    let res = io::stdin().read_line(&mut input).map(|n| if n == 0 { None } else { Some(input) });
+                    let _ = tx.send(res);
+                });
+                match rx.recv_timeout(timeout) {
+                    Ok(Ok(answer)) => {
+                        if let Some(confirmed) = self.evaluate(answer.as_deref()) {
+                            return Ok(confirmed);
+                        }
+                    }
+                    Ok(Err(e)) => return Err(Error::with_chain(e, ErrorKind::FailedToReadConfirmation)),
+                    Err(_) => return self.default.ok_or_else(|| ErrorKind::Timeout.into()),
+                }
+            }
+
+            Ok(false)
+        }
+
+        fn evaluate(&self, answer: Option<&str>) -> Option<bool> {
+            let answer = match answer {
+                Some(answer) => answer.trim(),
+                None => return Some(self.default.unwrap_or(false)),
+            };
+            if answer.is_empty() {
+                return self.default;
+            }
+            let matches = |accepted: &str| if self.case_insensitive {
+                accepted.to_lowercase() == answer.to_lowercase()
+            } else {
+                accepted == answer
+            };
+            let confirmed = if self.accepted.is_empty() {
+                matches("yes")
+            } else {
+                self.accepted.iter().any(|accepted| matches(accepted))
+            };
+
+            Some(confirmed)
+        }
+    }
+
+    const CLEAR_PREVIOUS_LINE: &str = "\x1b[1A\x1b[2K\r";
+
+    /// Asks one question per item with the rules of a `Confirm`, prefixed by a running tally like `[4/10, 3 confirmed]`.
+    /// In place mode, the default when stdout is a terminal, each prompt overwrites the previous one; otherwise the
+    /// prompts are written one after another. Timeouts of the `Confirm` are not applied.
+    #[derive(Clone, Debug)]
+    pub struct ConfirmSeries {
+        confirm: Confirm,
+        total: usize,
+        asked: usize,
+        confirmed: usize,
+        in_place: bool,
+    }
+
+    impl ConfirmSeries {
+        pub fn new(confirm: Confirm, total: usize) -> Self {
+            ConfirmSeries {
+                confirm,
+                total,
+                asked: 0,
+                confirmed: 0,
+                in_place: io::stdout().is_terminal(),
+            }
+        }
+
+        pub fn in_place(mut self, in_place: bool) -> Self {
+            self.in_place = in_place;
+            self
+        }
+
+        pub fn asked(&self) -> usize {
+            self.asked
+        }
+
+        pub fn confirmed(&self) -> usize {
+            self.confirmed
+        }
+
+        pub fn ask(&mut self, question: &str) -> Result<bool> {
+            if let Some(decision) = decide_unattended(question, self.confirm.default, true)? {
+                return Ok(self.count(decision));
+            }
+            let mut reader = input();
+            let mut writer = io::stdout();
+            self.ask_from(&mut reader, &mut writer, question)
+        }
+
+        pub fn ask_from<R: BufRead, W: Write>(&mut self, reader: &mut R, writer: &mut W, question: &str) -> Result<bool> {
+            let mut confirm = self.confirm.clone();
+            confirm.prompt = format!("{}[{}/{}, {} confirmed] {}",
+                self.redraw_prefix(), self.asked + 1, self.total, self.confirmed, question);
+            let confirmed = confirm.ask_from(reader, writer)?;
+
+            Ok(self.count(confirmed))
+        }
+
+        /// Replaces the last prompt with the final tally, e.g. `3/10 confirmed`.
+        pub fn finish(&self) -> Result<()> {
+            self.finish_from(&mut io::stdout())
+        }
+
+        pub fn finish_from<W: Write>(&self, writer: &mut W) -> Result<()> {
+            write_prompt(writer, &format!("{}{}/{} confirmed\n", self.redraw_prefix(), self.confirmed, self.total))
+        }
+
+        fn redraw_prefix(&self) -> &'static str {
+            if self.in_place && self.asked > 0 { CLEAR_PREVIOUS_LINE } else { "" }
+        }
+
+        fn count(&mut self, confirmed: bool) -> bool {
+            self.asked += 1;
+            if confirmed {
+                self.confirmed += 1;
+            }
+            confirmed
+        }
+    }
+
+    /// Lists `options` as checkboxes preselected by `defaults` and toggles the entered numbers until an empty
+    /// answer confirms; returns the indices of the selected options.
+    pub fn multi_select(prompt: &str, options: &[&str], defaults: &[bool]) -> Result<Vec<usize>> {
+        let preselected: Vec<usize> = (0..options.len()).filter(|i| defaults.get(*i).cloned().unwrap_or(false)).collect();
+        if let Some(decision) = decide_unattended(prompt, None, preselected)? {
+            return Ok(decision);
+        }
+        let mut reader = input();
+        let mut writer = io::stdout();
+        multi_select_from(&mut reader, &mut writer, prompt, options, defaults)
+    }
+
+    pub fn multi_select_from<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, prompt: &str, options: &[&str], defaults: &[bool]) -> Result<Vec<usize>> {
+        let mut selected: Vec<bool> = (0..options.len()).map(|i| defaults.get(i).cloned().unwrap_or(false)).collect();
+        loop {
+            let mut listing = format!("{}\n", prompt);
+            for (i, option) in options.iter().enumerate() {
+                listing.push_str(&format!("  [{}] {}) {}\n", if selected[i] { "x" } else { " " }, i + 1, option));
+            }
+            listing.push_str("Toggle by number, empty answer when done: ");
+
+            let answer = match read_answer(reader, writer, &listing)? {
+                Some(answer) => answer,
+                None => break,
+            };
+            if answer.trim().is_empty() {
+                break;
+            }
+            let choices: Vec<Option<usize>> = answer.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|choice| !choice.is_empty())
+                .map(|choice| choice.parse::<usize>().ok().filter(|n| *n >= 1 && *n <= options.len()))
+                .collect();
+            if choices.iter().any(Option::is_none) {
+                write_prompt(writer, &format!("Invalid choice '{}'\n", answer.trim()))?;
+                continue;
+            }
+            for n in choices.into_iter().flatten() {
+                selected[n - 1] = !selected[n - 1];
+            }
+        }
+
+        Ok(selected.iter().enumerate().filter(|(_, selected)| **selected).map(|(i, _)| i).collect())
+    }
+
+    pub fn confirm_then<F: FnOnce() -> T, T>(prompt: &str, expected: &str, spinner_msg: &str, work: F) -> Result<Option<T>> {
+        if let Some(decision) = decide_unattended(prompt, None, true)? {
+            return run_confirmed(decision, spinner_msg, work);
+        }
+        let in_place = io::stdout().is_terminal();
+        confirm_then_in(&mut input(), &mut io::stdout(), in_place, prompt, expected, spinner_msg, work)
+    }
+
+    pub fn confirm_then_from<R: BufRead, W: Write, F: FnOnce() -> T, T>(
+        reader: &mut R,
+        writer: &mut W,
+        prompt: &str,
+        expected: &str,
+        spinner_msg: &str,
+        work: F
+    ) -> Result<Option<T>> {
+        confirm_then_in(reader, writer, false, prompt, expected, spinner_msg, work)
+    }
+
+    /// Like `confirm_then_from` but replaces the answered prompt with the spinner if `in_place`.
+    fn confirm_then_in<R: BufRead, W: Write, F: FnOnce() -> T, T>(
+        reader: &mut R,
+        writer: &mut W,
+        in_place: bool,
+        prompt: &str,
+        expected: &str,
+        spinner_msg: &str,
+        work: F
+    ) -> Result<Option<T>> {
+        let confirmed = ask_for_confirmation_from(reader, writer, prompt, expected)?;
+        if confirmed && in_place {
+            write_prompt(writer, CLEAR_PREVIOUS_LINE)?;
+        }
+        run_confirmed(confirmed, spinner_msg, work)
+    }
+
+    fn run_confirmed<F: FnOnce() -> T, T>(confirmed: bool, spinner_msg: &str, work: F) -> Result<Option<T>> {
+        if !confirmed {
+            return Ok(None);
+        }
+
+        let spinner = progress::new_spinner();
+        if is_interactive() {
+            spinner.set_style(ProgressStyle::default_clams_spinner());
+            spinner.set_message(spinner_msg);
+            spinner.enable_steady_tick(100);
+        }
+        let res = work();
+        spinner.finish_and_clear();
+
+        Ok(Some(res))
+    }
+
+    /// Batch mode: prompts are not shown but decided by the `Unattended` policy instead of blocking; this is
+    /// also the case whenever stdin is not a terminal, unless the policy is `Unattended::ReadPiped`.
+    pub fn set_interactive(on: bool) {
+        INTERACTIVE.store(on, Ordering::SeqCst);
+    }
+
+    pub fn is_interactive() -> bool {
+        INTERACTIVE.load(Ordering::SeqCst)
+    }
+
+    /// How prompts that cannot be shown are answered.
+    #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+    pub enum Unattended {
+        /// Fail with `ErrorKind::NonInteractive`; a `Confirm` with a default answers with its default.
+        #[default]
+        Fail,
+        /// Confirm every prompt and keep the preselection of `multi_select`, e.g. for a `--yes` flag.
+        AssumeYes,
+        /// Read answers from stdin even if it is not a terminal, e.g. for `yes | tool`; batch mode still fails.
+        ReadPiped,
+    }
+
+    static UNATTENDED: RwLock<Unattended> = RwLock::new(Unattended::Fail);
+
+    pub fn set_unattended(policy: Unattended) {
+        *UNATTENDED.write().unwrap_or_else(|e| e.into_inner()) = policy;
+    }
+
+    pub fn unattended() -> Unattended {
+        *UNATTENDED.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Prompts need scripted input or interactive mode with stdin attached to a terminal or explicitly allowed to
+    /// be piped; a pipe or `/dev/null` would otherwise read as an empty answer and silently decline.
+    fn can_prompt(scripted: bool, interactive: bool, terminal: bool, policy: Unattended) -> bool {
+        scripted || (interactive && (terminal || policy == Unattended::ReadPiped))
+    }
+
+    /// Returns the answer for a prompt that cannot be shown and logs it, or `None` if the prompt should be shown.
+    fn decide_unattended<T: fmt::Debug>(prompt: &str, default: Option<T>, assumed: T) -> Result<Option<T>> {
+        let policy = unattended();
+        if can_prompt(is_scripted(), is_interactive(), io::stdin().is_terminal(), policy) {
+            return Ok(None);
+        }
+        let (decision, reason) = match (policy, default) {
+            (Unattended::AssumeYes, _) => (assumed, "assuming yes"),
+            (_, Some(default)) => (default, "using the default"),
+            (_, None) => return Err(ErrorKind::NonInteractive.into()),
+        };
+        log::info!("Not prompting '{}' in non-interactive mode, {}: {:?}", prompt.trim(), reason, decision);
+
+        Ok(Some(decision))
+    }
+
+    /// Colors `text` with the success color of the current `Theme`.
+    pub fn success<S: AsRef<str>>(text: S) -> ColoredString {
+        text.as_ref().color(theme::current().success)
+    }
+
+    pub fn warning<S: AsRef<str>>(text: S) -> ColoredString {
+        text.as_ref().color(theme::current().warning)
+    }
+
+    pub fn failure<S: AsRef<str>>(text: S) -> ColoredString {
+        text.as_ref().color(theme::current().failure)
+    }
+
+    pub fn set_color_off() {
+        set_color(false);
+    }
+
+    pub fn set_color(on: bool) {
+        colored::control::set_override(on); 
+    }
+
+    error_chain! {
+        errors {
+            FailedToReadConfirmation {
+                description("Failed to read confirmation")
+            }
+            NonInteractive {
+                description("Cannot prompt for input in non-interactive mode")
+            }
+            Timeout {
+                description("Timed out waiting for confirmation")
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        use quickcheck::{quickcheck, TestResult};
+        use spectral::prelude::*;
+        use crate::test_util::INTERACTIVE_LOCK;
+        use std::io::BufWriter;
+
+        #[test]
+        fn ask_for_yes_from_okay() {
+            let answer = "yes".to_owned();
+            let mut input = BufReader::new(answer.as_bytes());
+            let output_buf = Vec::new();
+            let mut output = BufWriter::new(output_buf);
+
+            let res = ask_for_confirmation_from(&mut input, &mut output, "This is just a test prompt: ", "yes");
+
+            assert_that(&res).is_ok().is_true();
+        }
+
+        #[test]
+        fn ask_for_yes_reader_quick() {
+            fn prop(x: String) -> TestResult {
+                let expected = "yes";
+
+                if x.len() > 3 || x == expected {
+                    return TestResult::discard();
+                }
+
+                let mut input = BufReader::new(x.as_bytes());
+                let output_buf = Vec::new();
+                let mut output = BufWriter::new(output_buf);
+
+                let res = ask_for_confirmation_from(&mut input, &mut output, "This is just a test prompt: ", expected)
+                    .unwrap();
+
+                TestResult::from_bool(!res)
+            }
+
+            quickcheck(prop as fn(String) -> TestResult);
+        }
+
+        #[test]
+        fn ask_for_any_from_okay() {
+            let accepted = ["production", "4f1c2a"];
+
+            for answer in &["production\n", "4f1c2a\n"] {
+                let mut input = BufReader::new(answer.as_bytes());
+                let mut output = Vec::new();
+
+                let res = ask_for_confirmation_any_from(&mut input, &mut output, "Type the cluster name or its id: ", &accepted);
+
+                assert_that(&res).is_ok().is_true();
+            }
+        }
+
+        #[test]
+        fn ask_for_any_from_declined() {
+            let mut input = BufReader::new("staging\n".as_bytes());
+            let mut output = Vec::new();
+
+            let res = ask_for_confirmation_any_from(&mut input, &mut output, "Type the cluster name or its id: ", &["production", "4f1c2a"]);
+
+            assert_that(&res).is_ok().is_false();
+        }
+
+        #[test]
+        fn ask_for_confirmation_non_interactive() {
+            let _lock = INTERACTIVE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            set_interactive(false);
+            let res = ask_for_confirmation("This is just a test prompt: ", "yes");
+            set_interactive(true);
+
+            match res {
+                Err(Error(ErrorKind::NonInteractive, _)) => {}
+                other => panic!("Expected NonInteractive, got {:?}", other),
+            }
+        }
+
+        fn confirm_from(confirm: &Confirm, answers: &str) -> Result<bool> {
+            let mut input = BufReader::new(answers.as_bytes());
+            let mut output = Vec::new();
+            confirm.ask_from(&mut input, &mut output)
+        }
+
+        #[test]
+        fn confirm_defaults() {
+            let confirm = Confirm::new("Proceed? ");
+
+            assert_that(&confirm_from(&confirm, "yes\n")).is_ok().is_true();
+            assert_that(&confirm_from(&confirm, "YES\n")).is_ok().is_false();
+            assert_that(&confirm_from(&confirm, "\n")).is_ok().is_false();
+            assert_that(&confirm_from(&confirm, "")).is_ok().is_false();
+        }
+
+        #[test]
+        fn confirm_default_answer() {
+            let confirm = Confirm::new("Proceed? [Y/n] ").default(true);
+
+            assert_that(&confirm_from(&confirm, "\n")).is_ok().is_true();
+            assert_that(&confirm_from(&confirm, "")).is_ok().is_true();
+            assert_that(&confirm_from(&confirm, "no\n")).is_ok().is_false();
+        }
+
+        #[test]
+        fn confirm_case_insensitive_accepted() {
+            let confirm = Confirm::new("Proceed? ").accept("y").accept("yes").case_insensitive();
+
+            assert_that(&confirm_from(&confirm, "Y\n")).is_ok().is_true();
+            assert_that(&confirm_from(&confirm, "Yes\n")).is_ok().is_true();
+            assert_that(&confirm_from(&confirm, "n\n")).is_ok().is_false();
+        }
+
+        #[test]
+        fn confirm_retries_empty_answers() {
+            assert_that(&confirm_from(&Confirm::new("Proceed? ").retries(2), "\n\nyes\n")).is_ok().is_true();
+            assert_that(&confirm_from(&Confirm::new("Proceed? ").retries(1), "\n\nyes\n")).is_ok().is_false();
+        }
+
+        #[test]
+        fn script_input_feeds_prompts_in_order() {
+            let _lock = INTERACTIVE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            set_interactive(false);
+            let script = script_input(vec!["yes", "", "production"]);
+
+            let first = ask_for_confirmation("Proceed? ", "yes");
+            let second = Confirm::new("Really? [Y/n] ").default(true).ask();
+            let remaining = script.remaining();
+            let third = ask_for_confirmation_any("Environment? ", &["staging", "production"]);
+            let exhausted = ask_for_confirmation("Again? ", "yes");
+            drop(script);
+            let unscripted = ask_for_confirmation("Proceed? ", "yes");
+            set_interactive(true);
+
+            assert_that(&first).is_ok().is_true();
+            assert_that(&second).is_ok().is_true();
+            assert_that(&remaining).is_equal_to(1);
+            assert_that(&third).is_ok().is_true();
+            assert_that(&exhausted).is_ok().is_false();
+            assert_that(&unscripted).is_err();
+        }
+
+        fn multi_select_with(answers: &str, defaults: &[bool]) -> (Result<Vec<usize>>, String) {
+            let mut input = BufReader::new(answers.as_bytes());
+            let mut output = Vec::new();
+            let res = multi_select_from(&mut input, &mut output, "Features?", &["tls", "metrics", "tracing"], defaults);
+            (res, String::from_utf8(output).expect("Not UTF-8"))
+        }
+
+        #[test]
+        fn multi_select_toggles_until_done() {
+            let (res, output) = multi_select_with("1 3\n3\n2,3\n\n", &[false, true]);
+
+            assert_that(&res).is_ok().is_equal_to(vec![0, 2]);
+            assert_that(&output.contains("  [x] 2) metrics\n")).is_true();
+        }
+
+        #[test]
+        fn multi_select_rejects_invalid_choices() {
+            let (res, output) = multi_select_with("1 7\nfoo\n", &[]);
+
+            assert_that(&res).is_ok().is_equal_to(Vec::new());
+            assert_that(&output.contains("Invalid choice '1 7'")).is_true();
+            assert_that(&output.contains("Invalid choice 'foo'")).is_true();
+        }
+
+        #[test]
+        fn confirm_series_redraws_in_place() {
+            let mut input = BufReader::new("yes\nno\nyes\n".as_bytes());
+            let mut output = Vec::new();
+            let mut series = ConfirmSeries::new(Confirm::new(""), 3).in_place(true);
+
+            let answers: Vec<bool> = ["a.mkv? ", "b.mkv? ", "c.mkv? "].iter()
+                .map(|question| series.ask_from(&mut input, &mut output, question).expect("Could not ask"))
+                .collect();
+            series.finish_from(&mut output).expect("Could not finish");
+
+            assert_that(&answers).is_equal_to(vec![true, false, true]);
+            assert_that(&series.confirmed()).is_equal_to(2);
+            assert_that(&String::from_utf8(output).expect("Not UTF-8")).is_equal_to(format!(
+                "[1/3, 0 confirmed] a.mkv? {0}[2/3, 1 confirmed] b.mkv? {0}[3/3, 1 confirmed] c.mkv? {0}2/3 confirmed\n",
+                CLEAR_PREVIOUS_LINE
+            ));
+        }
+
+        #[test]
+        fn confirm_series_sequential_fallback() {
+            let mut input = BufReader::new("\nno\n".as_bytes());
+            let mut output = Vec::new();
+            let mut series = ConfirmSeries::new(Confirm::new("").default(true), 2).in_place(false);
+
+            assert_that(&series.ask_from(&mut input, &mut output, "a.mkv? ")).is_ok().is_true();
+            assert_that(&series.ask_from(&mut input, &mut output, "b.mkv? ")).is_ok().is_false();
+            series.finish_from(&mut output).expect("Could not finish");
+
+            assert_that(&String::from_utf8(output).expect("Not UTF-8").as_str())
+                .is_equal_to("[1/2, 0 confirmed] a.mkv? [2/2, 1 confirmed] b.mkv? 1/2 confirmed\n");
+        }
+
+        #[test]
+        fn can_prompt_without_terminal() {
+            assert_that(&can_prompt(false, true, false, Unattended::Fail)).is_false();
+            assert_that(&can_prompt(false, true, false, Unattended::AssumeYes)).is_false();
+            assert_that(&can_prompt(false, true, false, Unattended::ReadPiped)).is_true();
+            assert_that(&can_prompt(false, false, true, Unattended::ReadPiped)).is_false();
+            assert_that(&can_prompt(true, false, false, Unattended::Fail)).is_true();
+            assert_that(&can_prompt(false, true, true, Unattended::Fail)).is_true();
+        }
+
+        #[test]
+        fn unattended_assume_yes() {
+            let _lock = INTERACTIVE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            set_interactive(false);
+            set_unattended(Unattended::AssumeYes);
+            let confirmed = ask_for_confirmation("Proceed? ", "yes");
+            let overrides_default = Confirm::new("Proceed? ").default(false).ask();
+            let selected = multi_select("Features?", &["tls", "metrics"], &[false, true]);
+            set_unattended(Unattended::Fail);
+            let failed = ask_for_confirmation("Proceed? ", "yes");
+            set_interactive(true);
+
+            assert_that(&confirmed).is_ok().is_true();
+            assert_that(&overrides_default).is_ok().is_true();
+            assert_that(&selected).is_ok().is_equal_to(vec![1]);
+            match failed {
+                Err(Error(ErrorKind::NonInteractive, _)) => {}
+                other => panic!("Expected NonInteractive, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn confirm_ask_timed_from() {
+            let mut input = BufReader::new("yes\n".as_bytes());
+            let mut output = Vec::new();
+
+            let res = Confirm::new("Proceed? ").ask_timed_from(&mut input, &mut output);
+
+            assert_that(&res).is_ok().map(|(confirmed, _)| confirmed).is_true();
+            assert_that(&res).is_ok().map(|(_, elapsed)| elapsed).is_less_than(Duration::from_secs(5));
+        }
+
+        #[test]
+        fn confirm_non_interactive_uses_default() {
+            let _lock = INTERACTIVE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            set_interactive(false);
+            let with_default = Confirm::new("Proceed? ").default(true).ask();
+            let without_default = Confirm::new("Proceed? ").ask();
+            set_interactive(true);
+
+            assert_that(&with_default).is_ok().is_true();
+            assert_that(&without_default).is_err();
+        }
+
+        #[test]
+        fn confirm_then_from_confirmed() {
+            let mut input = BufReader::new("yes".as_bytes());
+            let mut output = Vec::new();
+
+            let res = confirm_then_from(&mut input, &mut output, "Proceed? ", "yes", "Working", || 42);
+
+            assert_that(&res).is_ok().is_some().is_equal_to(42);
+        }
+
+        #[test]
+        fn confirm_then_from_declined() {
+            let mut input = BufReader::new("no".as_bytes());
+            let mut output = Vec::new();
+            let mut ran = false;
+
+            let res = confirm_then_from(&mut input, &mut output, "Proceed? ", "yes", "Working", || ran = true);
+
+            assert_that(&res).is_ok().is_none();
+            assert_that(&ran).is_false();
+        }
+    }
+}
+
+pub mod fs {
+    use crate::progress::Progress;
+
+    use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::time::{Duration, SystemTime};
+    use tail;
+
+    pub fn file_exists<T: AsRef<Path>>(path: T) -> bool {
+        path.as_ref().exists()
+    }
+
+    pub fn home_dir() -> Option<PathBuf> {
+        env::home_dir()
+    }
+
+    pub fn ensure_parent_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+        let parent = match path.as_ref().parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => return Ok(()),
+        };
+        if let Some(existing) = parent.ancestors().find(|ancestor| ancestor.exists()) {
+            if !existing.is_dir() {
+                return Err(io::Error::other(
+                    format!("Cannot create '{}' because '{}' is not a directory", parent.display(), existing.display())
+                ));
+            }
+        }
+
+        fs::create_dir_all(parent)
+    }
+
+    /// Writes `contents` to a temporary sibling of `path`, creating missing parents, and renames it over `path`,
+    /// so readers and crashes never see a partially written file.
+    pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+        let path = path.as_ref();
+        let file_name = path.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' has no file name", path.display())))?;
+        let tmp_path = path.with_file_name(format!(".{}.{}.tmp", file_name.to_string_lossy(), process::id()));
+        ensure_parent_dir(path)?;
+
+        let written = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(contents.as_ref()).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, path));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+
+        written
+    }
+
+    /// Size in bytes; like the other metadata helpers this follows symlinks and describes their target.
+    pub fn file_size<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+        fs::metadata(path).map(|metadata| metadata.len())
+    }
+
+    /// Sums the sizes of `paths` and counts them; missing files fail with `NotFound` unless `skip_missing`,
+    /// in which case they are neither summed nor counted.
+    pub fn total_size<P: AsRef<Path>>(paths: &[P], skip_missing: bool) -> io::Result<(u64, usize)> {
+        let mut total = (0, 0);
+        for path in paths {
+            match file_size(path) {
+                Ok(size) => total = (total.0 + size, total.1 + 1),
+                Err(ref e) if skip_missing && e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(total)
+    }
+
+    pub fn modified_time<P: AsRef<Path>>(path: P) -> io::Result<SystemTime> {
+        fs::metadata(path)?.modified()
+    }
+
+    /// Time since the last modification; zero if the modification time lies in the future.
+    pub fn file_age<P: AsRef<Path>>(path: P) -> io::Result<Duration> {
+        let modified = modified_time(path)?;
+
+        Ok(SystemTime::now().duration_since(modified).unwrap_or_default())
+    }
+
+    /// Regular files directly in `dir`, following symlinks, sorted by path; `extensions` are matched case-insensitively.
+    pub fn list_files<P: AsRef<Path>>(dir: P, extensions: Option<&[&str]>) -> io::Result<Vec<PathBuf>> {
+        let matches = |path: &Path| match extensions {
+            None => true,
+            Some(extensions) => path.extension()
+                .and_then(|extension| extension.to_str())
+                .map(|extension| extensions.iter().any(|e| e.eq_ignore_ascii_case(extension)))
+                .unwrap_or(false),
+        };
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && matches(&path) {
+                files.push(path);
+            }
+        }
+        files.sort();
+
+        Ok(files)
+    }
+
+    /// Appends `line` and a newline in a single write while holding an exclusive advisory lock, so concurrent appenders don't interleave;
+    /// on non-Unix platforms only the single write in append mode is relied on.
+    pub fn append_line<P: AsRef<Path>>(path: P, line: &str) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let mut buf = String::with_capacity(line.len() + 1);
+        buf.push_str(line);
+        buf.push('\n');
+
+        #[cfg(unix)]
+        flock(&file, libc::LOCK_EX)?;
+        let written = file.write_all(buf.as_bytes()).and_then(|_| file.flush());
+        #[cfg(unix)]
+        flock(&file, libc::LOCK_UN)?;
+
+        written
+    }
+
+    #[cfg(unix)]
+    fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+    pub struct MoveOptions {
+        /// Creates missing parent directories of the destination.
+        pub create_parents: bool,
+        /// Replaces an existing destination instead of failing with `AlreadyExists`.
+        pub overwrite: bool,
+        /// Carries over access and modification times when falling back to copying.
+        pub preserve_times: bool,
+        /// Compares sizes after copying and keeps the source if they differ.
+        pub verify: bool,
+    }
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub enum MoveKind {
+        Renamed,
+        Copied,
+    }
+
+    /// Renames `from` to `to` and falls back to copy and remove across file systems; a partial copy is removed on failure.
+    pub fn move_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q, opts: MoveOptions) -> io::Result<MoveKind> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if !opts.overwrite && to.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("'{}' already exists", to.display())));
+        }
+        if opts.create_parents {
+            ensure_parent_dir(to)?;
+        }
+
+        match fs::rename(from, to) {
+            Ok(()) => Ok(MoveKind::Renamed),
+            Err(ref e) if crosses_devices(e) => copy_and_remove(from, to, opts).map(|_| MoveKind::Copied),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(unix)]
+    fn crosses_devices(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(libc::EXDEV)
+    }
+
+    #[cfg(windows)]
+    fn crosses_devices(e: &io::Error) -> bool {
+        const ERROR_NOT_SAME_DEVICE: i32 = 17;
+        e.raw_os_error() == Some(ERROR_NOT_SAME_DEVICE)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn crosses_devices(_: &io::Error) -> bool {
+        false
+    }
+
+    fn copy_and_remove(from: &Path, to: &Path, opts: MoveOptions) -> io::Result<()> {
+        let copied = copy_verified(from, to, opts);
+        if copied.is_err() {
+            let _ = fs::remove_file(to);
+        }
+        copied?;
+
+        fs::remove_file(from)
+    }
+
+    fn copy_verified(from: &Path, to: &Path, opts: MoveOptions) -> io::Result<()> {
+        let copied = fs::copy(from, to)?;
+        let metadata = fs::metadata(from)?;
+        if opts.verify && (copied != metadata.len() || file_size(to)? != metadata.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Size of '{}' differs from '{}'", to.display(), from.display())));
+        }
+        if opts.preserve_times {
+            let times = fs::FileTimes::new()
+                .set_accessed(metadata.accessed()?)
+                .set_modified(metadata.modified()?);
+            fs::OpenOptions::new().write(true).open(to)?.set_times(times)?;
+        }
+
+        Ok(())
+    }
+
+    const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+    /// Copies `from` to `to` in chunks, creating the destination's parent dirs and reporting copied bytes to `progress`.
+    pub fn copy_with_progress<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q, progress: &dyn Progress) -> io::Result<u64> {
+        let mut source = File::open(from.as_ref())?;
+        let metadata = source.metadata()?;
+        progress.set_length(metadata.len());
+        ensure_parent_dir(to.as_ref())?;
+        let mut destination = File::create(to.as_ref())?;
+
+        let mut buffer = vec![0; COPY_CHUNK_SIZE];
+        let mut copied = 0;
+        loop {
+            let n = match source.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            destination.write_all(&buffer[..n])?;
+            copied += n as u64;
+            progress.inc(n as u64);
+        }
+        destination.flush()?;
+        fs::set_permissions(to.as_ref(), metadata.permissions())?;
+        progress.finish();
+
+        Ok(copied)
+    }
+
+    /// Whether `for_each_line` continues with the next line.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub enum LineFlow {
+        Continue,
+        Stop,
+    }
+
+    /// Calls `f` with each line, without line ending, and its 1-based number until `f` returns `LineFlow::Stop`;
+    /// returns the number of lines passed to `f`. Fails with `InvalidData` on invalid UTF-8 like `BufRead::lines`.
+    pub fn for_each_line<P: AsRef<Path>, F: FnMut(&str, u64) -> LineFlow>(path: P, f: F) -> io::Result<u64> {
+        read_lines(path.as_ref(), None, f)
+    }
+
+    /// Like `for_each_line` but reports the consumed bytes of the file to `progress`.
+    pub fn for_each_line_with_progress<P: AsRef<Path>, F: FnMut(&str, u64) -> LineFlow>(path: P, progress: &dyn Progress, f: F) -> io::Result<u64> {
+        read_lines(path.as_ref(), Some(progress), f)
+    }
+
+    fn read_lines<F: FnMut(&str, u64) -> LineFlow>(path: &Path, progress: Option<&dyn Progress>, mut f: F) -> io::Result<u64> {
+        let file = File::open(path)?;
+        if let Some(progress) = progress {
+            progress.set_length(file.metadata()?.len());
+        }
+        let mut reader = BufReader::new(file);
+
+        let mut buffer = Vec::new();
+        let mut count = 0;
+        loop {
+            buffer.clear();
+            let n = reader.read_until(b'\n', &mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            if let Some(progress) = progress {
+                progress.inc(n as u64);
+            }
+            let line = std::str::from_utf8(&buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let line = line.strip_suffix('\n').map(|l| l.strip_suffix('\r').unwrap_or(l)).unwrap_or(line);
+            count += 1;
+            if f(line, count) == LineFlow::Stop {
+                break;
+            }
+        }
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+
+        Ok(count)
+    }
+
+    pub trait FileExt {
+        fn read_last_line(self) -> ::std::io::Result<String>;
+
+        /// Returns the line `n` lines before the last one, i.e. `n` is 0-based and 0 is the last line, or `None` if there are
+        /// fewer lines. A trailing newline ends the last line rather than starting an empty one; each further newline adds an empty line.
+        fn read_nth_last_line(self, n: usize) -> ::std::io::Result<Option<String>>;
+
+        fn last_line_matching<F: Fn(&str) -> bool>(self, pred: F) -> ::std::io::Result<Option<String>>;
+    }
+
+    impl FileExt for File {
+        fn read_last_line(self) -> ::std::io::Result<String> {
+            let mut fd = BufReader::new(self);
+            let line = tail_lines(&mut fd, 10).pop().unwrap_or_default();
+            Ok(line)
+        }
+
+        fn read_nth_last_line(self, n: usize) -> ::std::io::Result<Option<String>> {
+            let mut fd = BufReader::new(self);
+            // One extra line since `tail` miscounts files without a trailing newline and would return one line short.
+            let lines = tail_lines(&mut fd, n + 2);
+            let line = lines.len().checked_sub(n + 1).map(|i| lines[i].clone());
+            Ok(line)
+        }
+
+        fn last_line_matching<F: Fn(&str) -> bool>(self, pred: F) -> ::std::io::Result<Option<String>> {
+            let mut fd = BufReader::new(self);
+            let mut window = 10;
+            let mut previous = None;
+            loop {
+                let lines = tail_lines(&mut fd, window);
+                if let Some(line) = lines.iter().rev().find(|line| pred(line)) {
+                    return Ok(Some(line.clone()));
+                }
+                // `tail` may return a line less than asked for, so only a window that stopped growing has reached the start.
+                if previous == Some(lines.len()) {
+                    return Ok(None);
+                }
+                previous = Some(lines.len());
+                window *= 2;
+            }
+        }
+    }
+
+    fn tail_lines(fd: &mut BufReader<File>, num_of_lines: usize) -> Vec<String> {
+        let mut reader = tail::BackwardsReader::new(num_of_lines, fd);
+        let mut buffer = Vec::new();
+        {
+            let mut writer = BufWriter::new(&mut buffer);
+            reader.read_all(&mut writer);
+        }
+        String::from_utf8_lossy(&buffer).lines().map(|s| s.to_owned()).collect()
+    }
+
+    #[cfg(test)]
+    mod test {
+        pub use super::*;
+        pub use spectral::prelude::*;
+
+        mod file_exists {
+            use super::*;
+
+            #[test]
+            fn no_such_file() {
+                let file_name = "no_such.file";
+                let res = file_exists(file_name);
+                assert_that(&res).is_false();
+            }
+
+            #[test]
+            fn file_does_exists() {
+                let file_name = "tests/data/file.exists";
+                let res = file_exists(file_name);
+                assert_that(&res).is_true();
+            }
+        }
+
+        mod write_atomic {
+            use super::*;
+            use crate::test_util::test_dir;
+
+            #[test]
+            fn replaces_without_leftovers() {
+                let dir = test_dir("write_atomic_replaces_without_leftovers");
+                let path = dir.join("nested/file.txt");
+
+                let created = write_atomic(&path, "first");
+                let replaced = write_atomic(&path, "second");
+
+                assert_that(&created.is_ok()).is_true();
+                assert_that(&replaced.is_ok()).is_true();
+                assert_that(&fs::read_to_string(&path).expect("Could not read")).is_equal_to("second".to_owned());
+                assert_that(&fs::read_dir(dir.join("nested")).unwrap().count()).is_equal_to(1);
+            }
+        }
+
+        mod ensure_parent_dir {
+            use super::*;
+            use crate::test_util::test_dir;
+
+            #[test]
+            fn creates_missing_parents() {
+                let dir = test_dir("creates_missing_parents");
+                let path = dir.join("a/b/c.toml");
+
+                let res = ensure_parent_dir(&path);
+
+                assert_that(&res).is_ok();
+                assert_that(&dir.join("a/b").is_dir()).is_true();
+            }
+
+            #[test]
+            fn parent_is_a_file() {
+                let dir = test_dir("parent_is_a_file");
+                fs::write(dir.join("a"), "").expect("Could not write file");
+                let path = dir.join("a/b/c.toml");
+
+                let res = ensure_parent_dir(&path);
+
+                let expected = format!("Cannot create '{}' because '{}' is not a directory", dir.join("a/b").display(), dir.join("a").display());
+                assert_that(&res.map_err(|e| (e.kind(), e.to_string()))).is_err().is_equal_to((io::ErrorKind::Other, expected));
+            }
+        }
+
+        mod metadata {
+            use super::*;
+            use crate::test_util::test_dir;
+
+            #[test]
+            fn file_size_okay() {
+                let dir = test_dir("file_size_okay");
+                fs::write(dir.join("file"), "12345").expect("Could not write file");
+
+                assert_that(&file_size(dir.join("file")).expect("Could not stat")).is_equal_to(5);
+                assert_that(&file_size(dir.join("missing")).is_err()).is_true();
+            }
+
+            #[test]
+            fn total_size_okay() {
+                let dir = test_dir("total_size_okay");
+                fs::write(dir.join("a"), "12345").expect("Could not write file");
+                fs::write(dir.join("b"), "123").expect("Could not write file");
+                let paths = vec![dir.join("a"), dir.join("missing"), dir.join("b")];
+
+                assert_that(&total_size(&paths, true).expect("Could not stat")).is_equal_to((8, 2));
+                assert_that(&total_size(&paths, false).map_err(|e| e.kind())).is_err().is_equal_to(io::ErrorKind::NotFound);
+            }
+
+            #[test]
+            fn file_age_okay() {
+                let dir = test_dir("file_age_okay");
+                fs::write(dir.join("file"), "").expect("Could not write file");
+
+                let modified = modified_time(dir.join("file")).expect("Could not stat");
+                let age = file_age(dir.join("file")).expect("Could not stat");
+
+                assert_that(&modified).is_less_than_or_equal_to(SystemTime::now());
+                assert_that(&age).is_less_than(Duration::from_secs(60));
+            }
+        }
+
+        mod list_files {
+            use super::*;
+            use crate::test_util::test_dir;
+
+            #[test]
+            fn filters_and_sorts() {
+                let dir = test_dir("list_files_filters_and_sorts");
+                for name in &["c.mkv", "a.MP4", "b.mkv", "notes.txt", "README"] {
+                    fs::write(dir.join(name), "").expect("Could not write file");
+                }
+                fs::create_dir(dir.join("d.mkv")).expect("Could not create dir");
+
+                let res = list_files(&dir, Some(&["mkv", "mp4"]));
+
+                assert_that(&res.map_err(|e| e.kind())).is_ok()
+                    .is_equal_to(vec![dir.join("a.MP4"), dir.join("b.mkv"), dir.join("c.mkv")]);
+                assert_that(&list_files(&dir, None).expect("Could not list").len()).is_equal_to(5);
+            }
+        }
+
+        mod for_each_line {
+            use super::*;
+            use crate::progress::RecordingProgress;
+            use crate::test_util::test_dir;
+
+            #[test]
+            fn numbers_lines_and_stops_early() {
+                let dir = test_dir("numbers_lines_and_stops_early");
+                fs::write(dir.join("file"), "first\r\nsecond\nstop\nnever").expect("Could not write file");
+                let mut seen = Vec::new();
+
+                let res = for_each_line(dir.join("file"), |line, number| {
+                    seen.push((line.to_owned(), number));
+                    if line == "stop" { LineFlow::Stop } else { LineFlow::Continue }
+                });
+
+                assert_that(&res.map_err(|e| e.kind())).is_ok().is_equal_to(3);
+                assert_that(&seen).is_equal_to(vec![("first".to_owned(), 1), ("second".to_owned(), 2), ("stop".to_owned(), 3)]);
+            }
+
+            #[test]
+            fn reports_consumed_bytes() {
+                let dir = test_dir("for_each_line_reports_consumed_bytes");
+                fs::write(dir.join("file"), "a\nbb\nccc").expect("Could not write file");
+                let progress = RecordingProgress::new();
+
+                let res = for_each_line_with_progress(dir.join("file"), &progress, |_, _| LineFlow::Continue);
+
+                assert_that(&res.map_err(|e| e.kind())).is_ok().is_equal_to(3);
+                assert_that(&progress.position()).is_equal_to(8);
+                assert_that(&progress.finish_count()).is_equal_to(1);
+            }
+        }
+
+        mod append_line {
+            use super::*;
+            use crate::test_util::test_dir;
+            use std::thread;
+
+            #[test]
+            fn concurrent_appenders_do_not_interleave() {
+                let dir = test_dir("concurrent_appenders_do_not_interleave");
+                let path = dir.join("audit.log");
+                let line = |i: usize| format!("{}", i).repeat(1000);
+
+                let handles: Vec<_> = (0..8).map(|i| {
+                    let path = path.clone();
+                    thread::spawn(move || for _ in 0..10 { append_line(&path, &line(i)).expect("Could not append") })
+                }).collect();
+                for handle in handles {
+                    handle.join().expect("Appender panicked");
+                }
+
+                let content = fs::read_to_string(&path).expect("Could not read");
+                assert_that(&content.lines().count()).is_equal_to(80);
+                assert_that(&content.lines().all(|l| (0..8).any(|i| l == line(i)))).is_true();
+            }
+        }
+
+        mod move_file {
+            use super::*;
+            use crate::test_util::test_dir;
+
+            #[cfg(unix)]
+            #[test]
+            fn detects_cross_device_errors() {
+                assert_that(&crosses_devices(&io::Error::from_raw_os_error(libc::EXDEV))).is_true();
+                assert_that(&crosses_devices(&io::Error::from_raw_os_error(libc::ENOENT))).is_false();
+            }
+
+            #[test]
+            fn renames_and_creates_parents() {
+                let dir = test_dir("renames_and_creates_parents");
+                fs::write(dir.join("a.mkv"), "video").expect("Could not write file");
+                let opts = MoveOptions { create_parents: true, ..Default::default() };
+
+                let res = move_file(dir.join("a.mkv"), dir.join("movies/a.mkv"), opts);
+
+                assert_that(&res.map_err(|e| e.kind())).is_ok().is_equal_to(MoveKind::Renamed);
+                assert_that(&dir.join("a.mkv").exists()).is_false();
+                assert_that(&fs::read_to_string(dir.join("movies/a.mkv")).expect("Could not read")).is_equal_to("video".to_owned());
+            }
+
+            #[test]
+            fn refuses_to_overwrite() {
+                let dir = test_dir("refuses_to_overwrite");
+                fs::write(dir.join("a.mkv"), "new").expect("Could not write file");
+                fs::write(dir.join("b.mkv"), "old").expect("Could not write file");
+
+                let refused = move_file(dir.join("a.mkv"), dir.join("b.mkv"), MoveOptions::default());
+                let overwritten = move_file(dir.join("a.mkv"), dir.join("b.mkv"), MoveOptions { overwrite: true, ..Default::default() });
+
+                assert_that(&refused.map_err(|e| e.kind())).is_err().is_equal_to(io::ErrorKind::AlreadyExists);
+                assert_that(&overwritten.map_err(|e| e.kind())).is_ok().is_equal_to(MoveKind::Renamed);
+                assert_that(&fs::read_to_string(dir.join("b.mkv")).expect("Could not read")).is_equal_to("new".to_owned());
+            }
+
+            #[test]
+            fn copy_and_remove_preserves_times() {
+                let dir = test_dir("copy_and_remove_preserves_times");
+                fs::write(dir.join("a.mkv"), "video").expect("Could not write file");
+                let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+                File::options().write(true).open(dir.join("a.mkv")).expect("Could not open")
+                    .set_modified(modified).expect("Could not set mtime");
+                let opts = MoveOptions { preserve_times: true, verify: true, ..Default::default() };
+
+                let res = copy_and_remove(&dir.join("a.mkv"), &dir.join("b.mkv"), opts);
+
+                assert_that(&res.is_ok()).is_true();
+                assert_that(&dir.join("a.mkv").exists()).is_false();
+                assert_that(&modified_time(dir.join("b.mkv")).expect("Could not stat")).is_equal_to(modified);
+            }
+
+            #[test]
+            fn copy_and_remove_cleans_up_on_failure() {
+                let dir = test_dir("copy_and_remove_cleans_up_on_failure");
+
+                let res = copy_and_remove(&dir.join("missing.mkv"), &dir.join("b.mkv"), MoveOptions::default());
+
+                assert_that(&res.is_err()).is_true();
+                assert_that(&dir.join("b.mkv").exists()).is_false();
+            }
+        }
+
+        mod copy_with_progress {
+            use super::*;
+            use crate::progress::{ProgressCall, RecordingProgress};
+            use crate::test_util::test_dir;
+
+            #[test]
+            fn copies_and_reports_bytes() {
+                let dir = test_dir("copies_and_reports_bytes");
+                let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+                fs::write(dir.join("source.bin"), &content).expect("Could not write file");
+                let recorder = RecordingProgress::new();
+
+                let res = copy_with_progress(dir.join("source.bin"), dir.join("nested/dest.bin"), &recorder);
+
+                assert_that(&res).is_ok().is_equal_to(200_000);
+                assert_that(&fs::read(dir.join("nested/dest.bin")).expect("Could not read file")).is_equal_to(content);
+                assert_that(&recorder.calls().first()).is_equal_to(Some(&ProgressCall::SetLength(200_000)));
+                assert_that(&recorder.position()).is_equal_to(200_000);
+                assert_that(&recorder.finish_count()).is_equal_to(1);
+            }
+        }
+
+        mod file_ext {
+            use super::*;
+            use crate::test_util::test_dir;
+
+            #[test]
+            fn read_last_line_okay() {
+                let file = File::open("tests/data/tail.txt").expect("Could not open tail.txt");
+
+                let last_line = file.read_last_line().expect("Could not read last line");
+
+                assert_that(&last_line).is_equal_to("-- Marcus Marcus Aurelius".to_owned());
+            }
+
+            #[test]
+            fn read_nth_last_line_okay() {
+                let file = File::open("tests/data/tail.txt").expect("Could not open tail.txt");
+
+                let line = file.read_nth_last_line(3).expect("Could not read line");
+
+                assert_that(&line).is_some().is_equal_to("Through him".to_owned());
+            }
+
+            #[test]
+            fn read_nth_last_line_trailing_newline() {
+                use crate::test_util::test_dir;
+
+                let dir = test_dir("read_nth_last_line_trailing_newline");
+                let read = |name: &str, content: &str, n: usize| {
+                    fs::write(dir.join(name), content).expect("Could not write file");
+                    File::open(dir.join(name)).expect("Could not open file").read_nth_last_line(n).expect("Could not read line")
+                };
+
+                assert_that(&read("with", "a\nb\n", 0)).is_some().is_equal_to("b".to_owned());
+                assert_that(&read("without", "a\nb", 0)).is_some().is_equal_to("b".to_owned());
+                assert_that(&read("without1", "a\nb", 1)).is_some().is_equal_to("a".to_owned());
+                assert_that(&read("without2", "a\nb", 2)).is_none();
+                assert_that(&read("blank", "a\nb\n\n", 0)).is_some().is_equal_to("".to_owned());
+                assert_that(&read("blank1", "a\nb\n\n", 1)).is_some().is_equal_to("b".to_owned());
+            }
+
+            #[test]
+            fn read_nth_last_line_beyond_start() {
+                let file = File::open("tests/data/tail.txt").expect("Could not open tail.txt");
+
+                let line = file.read_nth_last_line(4).expect("Could not read line");
+
+                assert_that(&line).is_none();
+            }
+
+            #[test]
+            fn last_line_matching_grows_window() {
+                let dir = test_dir("last_line_matching_grows_window");
+                let path = dir.join("log.txt");
+                let mut content = String::new();
+                for i in 0..1000 {
+                    let level = if i == 7 || i == 8 { "ERROR" } else { "INFO" };
+                    content.push_str(&format!("{} line {}\n", level, i));
+                }
+                fs::write(&path, content).expect("Could not write log");
+
+                let found = File::open(&path).unwrap().last_line_matching(|l| l.starts_with("ERROR"));
+                let missing = File::open(&path).unwrap().last_line_matching(|l| l.starts_with("TRACE"));
+
+                assert_that(&found).is_ok().is_some().is_equal_to("ERROR line 8".to_owned());
+                assert_that(&missing).is_ok().is_none();
+            }
+
+            #[test]
+            fn last_line_matching_without_trailing_newline() {
+                let dir = test_dir("last_line_matching_without_trailing_newline");
+                let path = dir.join("log.txt");
+                let content: Vec<String> = (0..100).map(|i| format!("{} line {}", if i == 3 { "ERROR" } else { "INFO" }, i)).collect();
+                fs::write(&path, content.join("\n")).expect("Could not write log");
+
+                let found = File::open(&path).unwrap().last_line_matching(|l| l.starts_with("ERROR"));
+
+                assert_that(&found).is_ok().is_some().is_equal_to("ERROR line 3".to_owned());
+            }
+        }
+    }
+}
+
+pub mod logging {
+    use crate::theme;
+
+    use error_chain::*;
+    use fern::{Dispatch, Output};
+    use fern::colors::ColoredLevelConfig;
+    use log;
+    use std::any::Any;
+    use std::backtrace::{Backtrace, BacktraceStatus};
+    use std::collections::{BTreeSet, VecDeque};
+    use std::fmt;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, IsTerminal};
+    use std::panic;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex, RwLock, Weak};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+
+    static MUTED_TARGETS: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+
+    /// Drops all records of `target` and its submodules until `unmute_target` is called; takes effect immediately.
+    pub fn mute_target(target: &str) {
+        MUTED_TARGETS.write().unwrap_or_else(|e| e.into_inner()).insert(target.to_owned());
+    }
+
+    pub fn unmute_target(target: &str) {
+        MUTED_TARGETS.write().unwrap_or_else(|e| e.into_inner()).remove(target);
+    }
+
+    fn is_muted(target: &str) -> bool {
+        let muted = MUTED_TARGETS.read().unwrap_or_else(|e| e.into_inner());
+        muted.iter().any(|m| target == m || (target.starts_with(m.as_str()) && target[m.len()..].starts_with("::")))
+    }
+
+    #[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+    pub struct Level(pub log::LevelFilter);
+
+    impl From<u64> for Level {
+        fn from(level: u64) -> Self {
+            match level {
+                0 => Level(log::LevelFilter::Warn),
+                1 => Level(log::LevelFilter::Info),
+                2 => Level(log::LevelFilter::Debug),
+                _ => Level(log::LevelFilter::Trace),
+            }
+        }
+    }
+
+    static ALL_LEVELS: [Level; 6] = [
+        Level(log::LevelFilter::Off),
+        Level(log::LevelFilter::Error),
+        Level(log::LevelFilter::Warn),
+        Level(log::LevelFilter::Info),
+        Level(log::LevelFilter::Debug),
+        Level(log::LevelFilter::Trace),
+    ];
+
+    impl Level {
+        pub fn all() -> &'static [Level] {
+            &ALL_LEVELS
+        }
+
+        pub fn named() -> impl Iterator<Item = (&'static str, Level)> {
+            ALL_LEVELS.iter().map(|level| (level.name(), level.clone()))
+        }
+
+        pub fn name(&self) -> &'static str {
+            match self.0 {
+                log::LevelFilter::Off => "off",
+                log::LevelFilter::Error => "error",
+                log::LevelFilter::Warn => "warn",
+                log::LevelFilter::Info => "info",
+                log::LevelFilter::Debug => "debug",
+                log::LevelFilter::Trace => "trace",
+            }
+        }
+    }
+
+    impl FromStr for Level {
+        type Err = Error;
+
+        fn from_str(s: &str) -> Result<Self> {
+            Level::named()
+                .find(|(name, _)| name.eq_ignore_ascii_case(s))
+                .map(|(_, level)| level)
+                .ok_or_else(|| ErrorKind::InvalidLevel(s.to_owned()).into())
+        }
+    }
+
+    /// Counts of `-v` and `-q` flags; with the `structopt` feature it can be `#[structopt(flatten)]`ed into a CLI.
+    #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+    #[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+    pub struct Verbosity {
+        /// Increases verbosity, may be repeated
+        #[cfg_attr(feature = "structopt", structopt(short = "v", long = "verbose", parse(from_occurrences)))]
+        pub verbose: u64,
+        /// Decreases verbosity, may be repeated
+        #[cfg_attr(feature = "structopt", structopt(short = "q", long = "quiet", parse(from_occurrences)))]
+        pub quiet: u64,
+    }
+
+    impl Verbosity {
+        /// Starts at `warn` like `Level::from(u64)`; each `-q` steps down towards `off`, each `-v` up towards `trace`.
+        pub fn level(&self) -> Level {
+            let net = self.verbose as i64 - self.quiet as i64;
+            match net {
+                n if n <= -2 => Level(log::LevelFilter::Off),
+                -1 => Level(log::LevelFilter::Error),
+                n => Level::from(n as u64),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct ModLevel {
+        pub module: String,
+        pub level: Level,
+    }
+
+    #[derive(Debug)]
+    pub struct Context<'a> {
+        timestamp: SystemTime,
+        context: Option<&'a str>,
+    }
+
+    impl<'a> Context<'a> {
+        pub fn timestamp(&self) -> SystemTime {
+            self.timestamp
+        }
+
+        pub fn context(&self) -> Option<&'a str> {
+            self.context
+        }
+    }
+
+    type FormatFn = dyn Fn(&mut fmt::Formatter, &log::Record, &Context) -> fmt::Result + Send + Sync;
+
+    #[derive(Clone)]
+    struct CustomFormat(Arc<FormatFn>);
+
+    impl fmt::Debug for CustomFormat {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("CustomFormat")
+        }
+    }
+
+    #[derive(Clone)]
+    struct Clock(Arc<dyn Fn() -> SystemTime + Send + Sync>);
+
+    impl Clock {
+        fn now(&self) -> SystemTime {
+            (self.0)()
+        }
+    }
+
+    impl Default for Clock {
+        fn default() -> Self {
+            Clock(Arc::new(SystemTime::now))
+        }
+    }
+
+    impl fmt::Debug for Clock {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("Clock")
+        }
+    }
+
+    struct CustomLine<'a, 'b> {
+        format: &'a CustomFormat,
+        record: &'a log::Record<'b>,
+        context: &'a Context<'a>,
+    }
+
+    impl<'a, 'b> fmt::Display for CustomLine<'a, 'b> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            (self.format.0)(f, self.record, self.context)
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct LogConfig {
+        out: Output,
+        color: bool,
+        default: Level,
+        levels: Vec<ModLevel>,
+        context: Option<String>,
+        format: Option<CustomFormat>,
+        max_message_len: Option<usize>,
+        show_location: bool,
+        clock: Clock,
+        dedup: Option<Duration>,
+        tee: Vec<LogConfig>,
+        target_style: TargetStyle,
+        github_actions: bool,
+    }
+
+    impl LogConfig {
+        pub fn new<T: Into<Output>>(out: T, color: bool, default: Level, levels: Vec<ModLevel>, context: Option<String>) -> Self {
+            LogConfig {
+                out: out.into(),
+                color,
+                default,
+                levels,
+                context: context.as_deref().map(sanitize_context),
+                format: None,
+                max_message_len: None,
+                show_location: false,
+                clock: Clock::default(),
+                dedup: None,
+                tee: Vec::new(),
+                target_style: TargetStyle::default(),
+                github_actions: false,
+            }
+        }
+
+        /// Emits warnings and errors as GitHub Actions workflow commands, e.g. `::error file=src/main.rs,line=42::...`,
+        /// so they show up as annotations; the location is included with `with_location`. Other levels stay plain.
+        pub fn with_github_actions(mut self) -> Self {
+            self.github_actions = true;
+            self
+        }
+
+        /// Shortens targets in the built-in layouts; custom formats see the full target.
+        pub fn with_target_style(mut self, target_style: TargetStyle) -> Self {
+            self.target_style = target_style;
+            self
+        }
+
+        /// Output to stdout, colored when interactive and stdout is a terminal, plus plain output to a file rotated at
+        /// `max_bytes`, keeping `keep` old files.
+        pub fn terminal_and_file<P: AsRef<Path>>(level: Level, path: P, max_bytes: u64, keep: usize) -> io::Result<Self> {
+            let file = LogConfig::new(rotating_file_output(path, max_bytes, keep)?, false, level.clone(), Vec::new(), None);
+            let colored = crate::console::is_interactive() && io::stdout().is_terminal();
+
+            Ok(LogConfig::new(io::stdout(), colored, level, Vec::new(), None).with_tee(file))
+        }
+
+        /// Additionally sends all records to `other`, which applies its own levels and formatting.
+        pub fn with_tee(mut self, other: LogConfig) -> Self {
+            self.tee.push(other);
+            self
+        }
+
+        /// Collapses consecutive records with identical target, level and message into one record followed by
+        /// "last message repeated N times", emitted on the next different record, by a background thread once `interval`
+        /// has passed since the last emitted line, and on `log::logger().flush()`.
+        pub fn with_dedup(mut self, interval: Duration) -> Self {
+            self.dedup = Some(interval);
+            self
+        }
+
+        /// Replaces the system clock used for timestamps, e.g. with a fixed time in tests. Only `with_format` layouts
+        /// print timestamps, so this has no effect on the built-in layouts; `with_dedup` intervals always use `Instant`.
+        pub fn with_clock<F: Fn() -> SystemTime + Send + Sync + 'static>(mut self, clock: F) -> Self {
+            self.clock = Clock(Arc::new(clock));
+            self
+        }
+
+        /// Appends the source file and line of the log statement, e.g. ` (src/main.rs:42)`.
+        pub fn with_location(mut self) -> Self {
+            self.show_location = true;
+            self
+        }
+
+        /// Truncates longer messages at a char boundary and notes how many bytes were dropped.
+        pub fn with_max_message_len(mut self, max_message_len: usize) -> Self {
+            self.max_message_len = Some(max_message_len);
+            self
+        }
+
+        /// Replaces the built-in line layout; `color` is ignored when a custom format is set.
+        pub fn with_format<F>(mut self, format: F) -> Self
+            where F: Fn(&mut fmt::Formatter, &log::Record, &Context) -> fmt::Result + Send + Sync + 'static
+        {
+            self.format = Some(CustomFormat(Arc::new(format)));
+            self
+        }
+    }
+
+
+    /// Escapes line breaks and tabs as `\n`, `\r`, `\t` and other control characters as `\u{..}` so a context
+    /// cannot break or forge log lines.
+    pub fn sanitize_context(context: &str) -> String {
+        let mut sanitized = String::with_capacity(context.len());
+        for c in context.chars() {
+            match c {
+                '\n' => sanitized.push_str("\\n"),
+                '\r' => sanitized.push_str("\\r"),
+                '\t' => sanitized.push_str("\\t"),
+                c if c.is_control() => sanitized.extend(c.escape_unicode()),
+                c => sanitized.push(c),
+            }
+        }
+
+        sanitized
+    }
+
+    pub fn init_logging(log_config: LogConfig) -> Result<()> {
+        dispatch(log_config)
+            .apply()
+            .map_err(|e| Error::with_chain(e, ErrorKind::FailedToInitLogging))?;
+
+        Ok(())
+    }
+
+    fn dispatch(log_config: LogConfig) -> Dispatch {
+        let tee = log_config.tee;
+        let dedup_interval = log_config.dedup;
+        let Level(default) = log_config.default;
+        let mut log_levels = Dispatch::new().level(default);
+
+        for md in log_config.levels.into_iter() {
+            let ModLevel { module, level } = md;
+            let Level(level) = level;
+            log_levels = log_levels.level_for(module, level);
+        }
+        log_levels = log_levels
+            .filter(|metadata| !is_muted(metadata.target()))
+            .chain(log_config.out);
+
+        let layout = Layout {
+            context: log_config.context.as_ref().map(|c| format!("[Context: {}] ", c)).unwrap_or_default(),
+            max_message_len: log_config.max_message_len,
+            show_location: log_config.show_location,
+            target_style: log_config.target_style,
+        };
+        let format = if let Some(format) = log_config.format {
+            format_custom(log_config.context, format, log_config.clock)
+        } else if log_config.github_actions {
+            format_github_actions(layout)
+        } else if log_config.color {
+            format_with_color(layout)
+        } else {
+            format_no_color(layout)
+        };
+        let formatted = format.chain(log_levels);
+        let formatted = match dedup_interval {
+            Some(interval) => dedup(formatted, interval),
+            None => formatted,
+        };
+
+        if tee.is_empty() {
+            formatted
+        } else {
+            tee.into_iter().fold(Dispatch::new().chain(formatted), |combined, other| combined.chain(dispatch(other)))
+        }
+    }
+
+    #[derive(Default)]
+    struct DedupState {
+        last: Option<(String, log::Level, String)>,
+        repeated: usize,
+        last_flush: Option<Instant>,
+    }
+
+    fn dedup(dispatch: Dispatch, interval: Duration) -> Dispatch {
+        let (level, inner) = dispatch.into_log();
+        let shared = Arc::new(DedupShared { inner, interval, state: Mutex::new(DedupState::default()) });
+        spawn_dedup_ticker(Arc::downgrade(&shared));
+        let dedup: Box<dyn log::Log> = Box::new(Dedup(shared));
+
+        Dispatch::new().level(level).chain(dedup)
+    }
+
+    /// Emits summaries that became due without a further record; ends once the logger is dropped.
+    fn spawn_dedup_ticker(shared: Weak<DedupShared>) {
+        let tick = match shared.upgrade() {
+            Some(shared) => (shared.interval / 4).max(Duration::from_millis(10)),
+            None => return,
+        };
+        let spawned = thread::Builder::new()
+            .name("clams-log-dedup".to_owned())
+            .spawn(move || loop {
+                thread::sleep(tick);
+                match shared.upgrade() {
+                    Some(shared) => shared.flush_due(Instant::now()),
+                    None => return,
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!("Could not spawn log dedup thread, repeats are summarized on the next record only: {}", e);
+        }
+    }
+
+    struct Dedup(Arc<DedupShared>);
+
+    struct DedupShared {
+        inner: Box<dyn log::Log>,
+        interval: Duration,
+        state: Mutex<DedupState>,
+    }
+
+    impl DedupShared {
+        fn flush_due(&self, now: Instant) {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.last_flush.map(|last_flush| now.duration_since(last_flush) >= self.interval).unwrap_or(true) {
+                flush_repeated(&*self.inner, &mut state, now);
+            }
+        }
+    }
+
+    impl log::Log for Dedup {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            self.0.inner.enabled(metadata)
+        }
+
+        fn log(&self, record: &log::Record) {
+            let shared = &*self.0;
+            if !shared.inner.enabled(record.metadata()) {
+                return;
+            }
+            let key = (record.target().to_owned(), record.level(), record.args().to_string());
+            let now = Instant::now();
+            let mut state = shared.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.last.as_ref() == Some(&key) {
+                state.repeated += 1;
+                drop(state);
+                shared.flush_due(now);
+                return;
+            }
+            flush_repeated(&*shared.inner, &mut state, now);
+            shared.inner.log(record);
+            state.last = Some(key);
+            state.last_flush = Some(now);
+        }
+
+        /// Emits a pending "last message repeated N times", e.g. on `log::logger().flush()` before exiting.
+        fn flush(&self) {
+            let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+            flush_repeated(&*self.0.inner, &mut state, Instant::now());
+            self.0.inner.flush();
+        }
+    }
+
+    fn flush_repeated(inner: &dyn log::Log, state: &mut DedupState, now: Instant) {
+        if state.repeated == 0 {
+            return;
+        }
+        if let Some((ref target, level, _)) = state.last {
+            let times = if state.repeated == 1 { "time" } else { "times" };
+            inner.log(&log::Record::builder()
+                .args(format_args!("last message repeated {} {}", state.repeated, times))
+                .level(level)
+                .target(target)
+                .build());
+        }
+        state.repeated = 0;
+        state.last_flush = Some(now);
+    }
+
+    #[derive(Clone, Debug)]
+    struct Layout {
+        context: String,
+        max_message_len: Option<usize>,
+        show_location: bool,
+        target_style: TargetStyle,
+    }
+
+    /// How the built-in layouts print record targets.
+    #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+    pub enum TargetStyle {
+        /// `my_app::subsystem::component::worker`
+        #[default]
+        Full,
+        /// `m::s::c::worker`: initials of all but the last segment.
+        Abbreviated,
+        /// `component::worker` for `LastSegments(2)`.
+        LastSegments(usize),
+    }
+
+    struct Target<'a> {
+        target: &'a str,
+        style: TargetStyle,
+    }
+
+    impl<'a> fmt::Display for Target<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.style {
+                TargetStyle::Full => f.write_str(self.target),
+                TargetStyle::Abbreviated => {
+                    let mut segments = self.target.split("::").peekable();
+                    while let Some(segment) = segments.next() {
+                        if segments.peek().is_none() {
+                            f.write_str(segment)?;
+                        } else if let Some(initial) = segment.chars().next() {
+                            write!(f, "{}::", initial)?;
+                        }
+                    }
+                    Ok(())
+                }
+                TargetStyle::LastSegments(n) => {
+                    let segments: Vec<&str> = self.target.split("::").collect();
+                    let skip = segments.len().saturating_sub(n.max(1));
+                    f.write_str(&segments[skip..].join("::"))
+                }
+            }
+        }
+    }
+
+    /// Opens `path` for logging, creating it if missing; existing content is kept if `append` and truncated otherwise.
+    /// Each record is written as one whole line, see `line_atomic_output`.
+    pub fn file_output<P: AsRef<Path>>(path: P, append: bool) -> io::Result<Output> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)?;
+
+        Ok(line_atomic_output(file))
+    }
+
+    /// Appends to `path` and renames it to `<path>.1` once the next line would exceed `max_bytes`, shifting older
+    /// files up to `<path>.<keep>`; with `keep` of 0 the file is truncated instead.
+    pub fn rotating_file_output<P: AsRef<Path>>(path: P, max_bytes: u64, keep: usize) -> io::Result<Output> {
+        Ok(line_atomic_output(RotatingFile::open(path.as_ref(), max_bytes, keep)?))
+    }
+
+    struct RotatingFile {
+        path: PathBuf,
+        max_bytes: u64,
+        keep: usize,
+        file: File,
+        size: u64,
+    }
+
+    impl RotatingFile {
+        fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<Self> {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let size = file.metadata()?.len();
+
+            Ok(RotatingFile { path: path.to_path_buf(), max_bytes, keep, file, size })
+        }
+
+        fn numbered(&self, n: usize) -> PathBuf {
+            let mut path = self.path.as_os_str().to_owned();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        }
+
+        fn rotate(&mut self) -> io::Result<()> {
+            if self.keep == 0 {
+                self.file = OpenOptions::new().write(true).truncate(true).open(&self.path)?;
+            } else {
+                let oldest = self.numbered(self.keep);
+                if oldest.exists() {
+                    fs::remove_file(&oldest)?;
+                }
+                for n in (1..self.keep).rev() {
+                    let from = self.numbered(n);
+                    if from.exists() {
+                        fs::rename(&from, self.numbered(n + 1))?;
+                    }
+                }
+                fs::rename(&self.path, self.numbered(1))?;
+                self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            }
+            self.size = 0;
+
+            Ok(())
+        }
+    }
+
+    impl io::Write for RotatingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+                self.rotate()?;
+            }
+            let n = self.file.write(buf)?;
+            self.size += n as u64;
+
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    /// Formats each record into a complete line first and hands it to `writer` in a single `write_all` under a lock,
+    /// so lines from concurrent threads never interleave; `writer` should be unbuffered.
+    pub fn line_atomic_output<W: io::Write + Send + 'static>(writer: W) -> Output {
+        let writer = Mutex::new(writer);
+        Output::call(move |record| {
+            let line = format!("{}\n", record.args());
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = writer.write_all(line.as_bytes()).and_then(|_| writer.flush());
+        })
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct RingHandle {
+        lines: Arc<Mutex<VecDeque<String>>>,
+    }
+
+    impl RingHandle {
+        pub fn lines(&self) -> Vec<String> {
+            self.lines.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+        }
+
+        pub fn clear(&self) {
+            self.lines.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        }
+    }
+
+    /// Retains the last `capacity` formatted lines; chain it next to the regular output via a `Dispatch`.
+    pub fn ring_buffer_output(capacity: usize) -> (Output, RingHandle) {
+        let lines = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        let handle = RingHandle { lines: lines.clone() };
+        let out = Output::call(move |record| {
+            if capacity == 0 {
+                return;
+            }
+            let line = record.args().to_string();
+            let mut lines = lines.lock().unwrap_or_else(|e| e.into_inner());
+            if lines.len() == capacity {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        });
+
+        (out, handle)
+    }
+
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub enum SocketAddress {
+        Tcp(SocketAddr),
+        #[cfg(unix)]
+        Unix(PathBuf),
+    }
+
+    impl SocketAddress {
+        /// Writes time out after `SOCKET_WRITE_TIMEOUT` so a collector that stops reading counts as disconnected.
+        fn connect(&self) -> io::Result<Box<dyn io::Write + Send>> {
+            match *self {
+                SocketAddress::Tcp(ref addr) => {
+                    let stream = TcpStream::connect_timeout(addr, SOCKET_CONNECT_TIMEOUT)?;
+                    stream.set_write_timeout(Some(SOCKET_WRITE_TIMEOUT))?;
+                    Ok(Box::new(stream))
+                }
+                #[cfg(unix)]
+                SocketAddress::Unix(ref path) => {
+                    let stream = std::os::unix::net::UnixStream::connect(path)?;
+                    stream.set_write_timeout(Some(SOCKET_WRITE_TIMEOUT))?;
+                    Ok(Box::new(stream))
+                }
+            }
+        }
+    }
+
+    const SOCKET_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+    const SOCKET_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+    const SOCKET_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+    /// Sends each formatted line to a collector from a background thread. Up to `capacity` lines are buffered;
+    /// when the buffer is full or the collector is unreachable lines are dropped instead of blocking, and the
+    /// number of dropped lines is reported once the connection is (re-)established. A collector that stops reading
+    /// is treated as disconnected once a write times out.
+    pub fn socket_output(addr: SocketAddress, capacity: usize) -> io::Result<Output> {
+        let (tx, rx) = mpsc::sync_channel::<String>(capacity);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let sender_dropped = dropped.clone();
+
+        thread::Builder::new()
+            .name("clams-log-socket".to_owned())
+            .spawn(move || {
+                let mut connection: Option<Box<dyn io::Write + Send>> = None;
+                let mut last_attempt: Option<Instant> = None;
+                for line in rx {
+                    if connection.is_none() && last_attempt.map(|last| last.elapsed() >= SOCKET_RECONNECT_DELAY).unwrap_or(true) {
+                        last_attempt = Some(Instant::now());
+                        connection = addr.connect().ok();
+                    }
+                    let written = match connection {
+                        Some(ref mut writer) => {
+                            let missed = dropped.swap(0, Ordering::Relaxed);
+                            let notice = if missed > 0 { format!("[clams] {} log lines dropped\n", missed) } else { String::new() };
+                            writer.write_all(format!("{}{}", notice, line).as_bytes()).and_then(|_| writer.flush()).is_ok()
+                        }
+                        None => false,
+                    };
+                    if !written {
+                        connection = None;
+                        dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })?;
+
+        Ok(Output::call(move |record| {
+            if tx.try_send(format!("{}\n", record.args())).is_err() {
+                sender_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }))
+    }
+
+    /// Routes panics through the logger at error level and then to the previously installed hook, which by default
+    /// prints to stderr; call after `init_logging`.
+    pub fn install_panic_hook() {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let thread = thread::current();
+            let thread = thread.name().unwrap_or("<unnamed>");
+            let message = panic_message(info.payload());
+            let location = info.location()
+                .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+                .unwrap_or_else(|| "unknown location".to_owned());
+
+            let backtrace = Backtrace::capture();
+            if backtrace.status() == BacktraceStatus::Captured {
+                log::error!(target: "panic", "thread '{}' panicked at '{}', {}\n{}", thread, message, location, backtrace);
+            } else {
+                log::error!(target: "panic", "thread '{}' panicked at '{}', {}", thread, message, location);
+            }
+            previous(info);
+        }));
+    }
+
+    fn panic_message(payload: &(dyn Any + Send)) -> &str {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            message
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.as_str()
+        } else {
+            "Box<dyn Any>"
+        }
+    }
+
+    fn format_custom(context: Option<String>, format: CustomFormat, clock: Clock) -> Dispatch {
+        Dispatch::new()
+            .format(move |out, _, record| {
+                let context = Context {
+                    timestamp: clock.now(),
+                    context: context.as_deref(),
+                };
+                out.finish(format_args!("{}", CustomLine { format: &format, record, context: &context }))
+            })
+    }
+
+    struct Message<'a> {
+        args: &'a fmt::Arguments<'a>,
+        max_len: Option<usize>,
+    }
+
+    impl<'a> fmt::Display for Message<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.max_len {
+                Some(max_len) => f.write_str(&truncate(self.args.to_string(), max_len)),
+                None => write!(f, "{}", self.args),
+            }
+        }
+    }
+
+    fn truncate(mut message: String, max_len: usize) -> String {
+        if message.len() <= max_len {
+            return message;
+        }
+        let mut end = max_len;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        let dropped = message.len() - end;
+        message.truncate(end);
+        message.push_str(&format!("… [{} bytes truncated]", dropped));
+
+        message
+    }
+
+    struct Location<'a, 'b> {
+        record: &'a log::Record<'b>,
+        show: bool,
+    }
+
+    impl<'a, 'b> fmt::Display for Location<'a, 'b> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match (self.show, self.record.file(), self.record.line()) {
+                (true, Some(file), Some(line)) => write!(f, " ({}:{})", file, line),
+                (true, Some(file), None) => write!(f, " ({})", file),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn format_with_color(layout: Layout) -> Dispatch {
+        let theme = theme::current();
+        let colors = ColoredLevelConfig::new()
+            .error(theme.error)
+            .warn(theme.warn)
+            .info(theme.info)
+            .debug(theme.debug)
+            .trace(theme.trace);
+        Dispatch::new()
+            .format(move |out, message, record| {
+                let level = format!("{}", record.level());
+                out.finish(format_args!(
+                    "{}{}{:padding$}{}: {}{}",
+                    layout.context,
+                    colors.color(record.level()),
+                    " ",
+                    Target { target: record.target(), style: layout.target_style },
+                    Message { args: message, max_len: layout.max_message_len },
+                    Location { record, show: layout.show_location },
+                    padding = 6 - level.len(),
+                ))
+            })
+    }
+
+    fn format_no_color(layout: Layout) -> Dispatch {
+        Dispatch::new()
+            .format(move |out, message, record| {
+                let level = format!("{}", record.level());
+                out.finish(format_args!(
+                    "{}{}{:padding$}{}: {}{}",
+                    layout.context,
+                    record.level(),
+                    " ",
+                    Target { target: record.target(), style: layout.target_style },
+                    Message { args: message, max_len: layout.max_message_len },
+                    Location { record, show: layout.show_location },
+                    padding = 6 - level.len(),
+                ))
+            })
+    }
+
+    fn format_github_actions(layout: Layout) -> Dispatch {
+        Dispatch::new()
+            .format(move |out, message, record| {
+                let command = match record.level() {
+                    log::Level::Error => "error",
+                    log::Level::Warn => "warning",
+                    level => {
+                        let padding = 6 - level.as_str().len();
+                        return out.finish(format_args!(
+                            "{}{}{:padding$}{}: {}",
+                            layout.context,
+                            level,
+                            " ",
+                            Target { target: record.target(), style: layout.target_style },
+                            Message { args: message, max_len: layout.max_message_len },
+                            padding = padding,
+                        ));
+                    }
+                };
+                let mut properties = Vec::new();
+                if layout.show_location {
+                    if let Some(file) = record.file() {
+                        properties.push(format!("file={}", escape_workflow_property(file)));
+                    }
+                    if let Some(line) = record.line() {
+                        properties.push(format!("line={}", line));
+                    }
+                }
+                let text = format!("{}{}: {}",
+                    layout.context,
+                    Target { target: record.target(), style: layout.target_style },
+                    Message { args: message, max_len: layout.max_message_len });
+                let separator = if properties.is_empty() { "" } else { " " };
+                out.finish(format_args!("::{}{}{}::{}", command, separator, properties.join(","), escape_workflow_data(&text)))
+            })
+    }
+
+    fn escape_workflow_data(data: &str) -> String {
+        data.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+    }
+
+    fn escape_workflow_property(property: &str) -> String {
+        escape_workflow_data(property).replace(':', "%3A").replace(',', "%2C")
+    }
+
+    error_chain! {
+        errors {
+            FailedToInitLogging {
+                description("Failed to init logging")
+            }
+            InvalidLevel(level: String) {
+                description("Invalid log level")
+                display("Invalid log level '{}'", level)
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        use spectral::prelude::*;
+
+        fn log_warning(log_config: LogConfig) {
+            log_warnings(log_config, &["a message"]);
+        }
+
+        fn log_warnings(log_config: LogConfig, messages: &[&str]) {
+            let (_, logger) = dispatch(log_config).into_log();
+            for message in messages {
+                logger.log(&log::Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(log::Level::Warn)
+                    .target("clams::test")
+                    .file(Some("src/test.rs"))
+                    .line(Some(42))
+                    .build());
+            }
+        }
+
+        fn capture() -> (Output, Arc<Mutex<Vec<String>>>) {
+            let lines = Arc::new(Mutex::new(Vec::new()));
+            let sink = lines.clone();
+            let out = Output::call(move |record| sink.lock().unwrap().push(format!("{}", record.args())));
+            (out, lines)
+        }
+
+        #[test]
+        fn dedup_collapses_repeated_lines() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None)
+                .with_dedup(Duration::from_secs(3600));
+
+            log_warnings(log_config, &["retrying", "retrying", "retrying", "giving up", "giving up"]);
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec![
+                "WARN  clams::test: retrying".to_owned(),
+                "WARN  clams::test: last message repeated 2 times".to_owned(),
+                "WARN  clams::test: giving up".to_owned(),
+            ]);
+        }
+
+        #[test]
+        fn dedup_flushes_periodically() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None)
+                .with_dedup(Duration::from_secs(0));
+
+            log_warnings(log_config, &["retrying", "retrying", "retrying"]);
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec![
+                "WARN  clams::test: retrying".to_owned(),
+                "WARN  clams::test: last message repeated 1 time".to_owned(),
+                "WARN  clams::test: last message repeated 1 time".to_owned(),
+            ]);
+        }
+
+        #[test]
+        fn dedup_emits_pending_repeats_after_interval() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None)
+                .with_dedup(Duration::from_millis(50));
+
+            let (_, logger) = dispatch(log_config).into_log();
+
+            for _ in 0..3 {
+                logger.log(&log::Record::builder().args(format_args!("retrying")).level(log::Level::Warn).target("clams::test").build());
+            }
+            thread::sleep(Duration::from_millis(500));
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec![
+                "WARN  clams::test: retrying".to_owned(),
+                "WARN  clams::test: last message repeated 2 times".to_owned(),
+            ]);
+        }
+
+        #[test]
+        fn dedup_flushes_pending_repeats() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None)
+                .with_dedup(Duration::from_secs(3600));
+            let (_, logger) = dispatch(log_config).into_log();
+
+            for _ in 0..3 {
+                logger.log(&log::Record::builder().args(format_args!("retrying")).level(log::Level::Warn).target("clams::test").build());
+            }
+            logger.flush();
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec![
+                "WARN  clams::test: retrying".to_owned(),
+                "WARN  clams::test: last message repeated 2 times".to_owned(),
+            ]);
+        }
+
+        #[test]
+        fn sanitize_context_okay() {
+            assert_that(&sanitize_context("GET /index.html").as_str()).is_equal_to("GET /index.html");
+            assert_that(&sanitize_context("a\nWARN  forged\r\tline").as_str()).is_equal_to("a\\nWARN  forged\\r\\tline");
+            assert_that(&sanitize_context("bell\u{7}").as_str()).is_equal_to("bell\\u{7}");
+        }
+
+        #[test]
+        fn context_is_sanitized() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), Some("evil\nWARN".to_owned()));
+
+            log_warning(log_config);
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec!["[Context: evil\\nWARN] WARN  clams::test: a message".to_owned()]);
+        }
+
+        #[derive(Clone, Default)]
+        struct Writes(Arc<Mutex<Vec<Vec<u8>>>>);
+
+        impl io::Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().push(buf.to_vec());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn line_atomic_output_writes_whole_lines() {
+            let writes = Writes::default();
+            let log_config = LogConfig::new(line_atomic_output(writes.clone()), false, Level(log::LevelFilter::Info), Vec::new(), None);
+            let (_, logger) = dispatch(log_config).into_log();
+            let logger: Arc<dyn log::Log> = Arc::from(logger);
+
+            let threads: Vec<_> = (0..8).map(|i| {
+                let logger = logger.clone();
+                thread::spawn(move || for j in 0..50 {
+                    logger.log(&log::Record::builder()
+                        .args(format_args!("thread {} message {}", i, j))
+                        .level(log::Level::Warn)
+                        .target("clams::test")
+                        .build());
+                })
+            }).collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            let writes = writes.0.lock().unwrap();
+            assert_that(&writes.len()).is_equal_to(400);
+            for write in writes.iter() {
+                let line = String::from_utf8_lossy(write);
+                assert_that(&(line.starts_with("WARN  clams::test: thread ") && line.ends_with('\n') && line.matches('\n').count() == 1)).is_true();
+            }
+        }
+
+        #[test]
+        fn socket_output_tcp() {
+            use std::io::BufRead;
+            use std::net::TcpListener;
+
+            let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind");
+            let addr = listener.local_addr().expect("No local address");
+            let log_config = LogConfig::new(socket_output(SocketAddress::Tcp(addr), 16).expect("Could not spawn"), false, Level(log::LevelFilter::Info), Vec::new(), None);
+
+            log_warnings(log_config, &["first", "second"]);
+
+            let (stream, _) = listener.accept().expect("Could not accept");
+            let lines: Vec<String> = io::BufReader::new(stream).lines().map(|line| line.expect("Could not read")).collect();
+            assert_that(&lines).is_equal_to(vec![
+                "WARN  clams::test: first".to_owned(),
+                "WARN  clams::test: second".to_owned(),
+            ]);
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn socket_output_tcp_reconnects_after_stalled_peer() {
+            use std::io::BufRead;
+            use std::net::TcpListener;
+
+            let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind");
+            let addr = listener.local_addr().expect("No local address");
+            let log_config = LogConfig::new(socket_output(SocketAddress::Tcp(addr), 16).expect("Could not spawn"), false, Level(log::LevelFilter::Info), Vec::new(), None);
+            let (_, logger) = dispatch(log_config).into_log();
+            let line = "x".repeat(64 * 1024);
+            let log = || logger.log(&log::Record::builder()
+                .args(format_args!("{}", line))
+                .level(log::Level::Warn)
+                .target("clams::test")
+                .build());
+
+            log();
+            let (_stalled, _) = listener.accept().expect("Could not accept");
+            listener.set_nonblocking(true).expect("Could not set non-blocking");
+
+            let deadline = Instant::now() + Duration::from_secs(30);
+            let reconnected = loop {
+                assert_that(&(Instant::now() < deadline)).is_true();
+                log();
+                match listener.accept() {
+                    Ok((stream, _)) => break stream,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(50)),
+                    Err(e) => panic!("Could not accept: {}", e),
+                }
+            };
+
+            reconnected.set_nonblocking(false).expect("Could not set blocking");
+            let mut first = String::new();
+            io::BufReader::new(reconnected).read_line(&mut first).expect("Could not read");
+            assert_that(&first).ends_with(" log lines dropped\n");
+        }
+
+        #[test]
+        fn socket_output_unix_unreachable_does_not_block() {
+            let dir = crate::test_util::test_dir("socket_output_unix_unreachable_does_not_block");
+            let log_config = LogConfig::new(socket_output(SocketAddress::Unix(dir.join("missing.sock")), 1).expect("Could not spawn"), false, Level(log::LevelFilter::Info), Vec::new(), None);
+
+            log_warnings(log_config, &["a"; 100]);
+        }
+
+        #[test]
+        fn with_tee_formats_per_output() {
+            let (plain, plain_lines) = capture();
+            let (verbose, verbose_lines) = capture();
+            let log_config = LogConfig::new(plain, false, Level(log::LevelFilter::Info), Vec::new(), None)
+                .with_tee(LogConfig::new(verbose, false, Level(log::LevelFilter::Info), Vec::new(), Some("tee".to_owned())).with_location());
+
+            log_warning(log_config);
+
+            assert_that(&*plain_lines.lock().unwrap()).is_equal_to(vec!["WARN  clams::test: a message".to_owned()]);
+            assert_that(&*verbose_lines.lock().unwrap()).is_equal_to(vec!["[Context: tee] WARN  clams::test: a message (src/test.rs:42)".to_owned()]);
+        }
+
+        #[test]
+        fn rotating_file_output_rotates() {
+            let dir = crate::test_util::test_dir("rotating_file_output_rotates");
+            let path = dir.join("app.log");
+            let log_config = LogConfig::new(rotating_file_output(&path, 64, 2).expect("Could not open"), false, Level(log::LevelFilter::Info), Vec::new(), None);
+
+            log_warnings(log_config, &["one", "two", "three", "four", "five"]);
+
+            let read = |path: &Path| fs::read_to_string(path).expect("Could not read");
+            assert_that(&read(&path)).is_equal_to("WARN  clams::test: five\n".to_owned());
+            assert_that(&read(&dir.join("app.log.1"))).is_equal_to("WARN  clams::test: three\nWARN  clams::test: four\n".to_owned());
+            assert_that(&read(&dir.join("app.log.2"))).is_equal_to("WARN  clams::test: one\nWARN  clams::test: two\n".to_owned());
+            assert_that(&dir.join("app.log.3").exists()).is_false();
+        }
+
+        #[test]
+        fn terminal_and_file_uncolored_when_not_interactive() {
+            let _lock = crate::test_util::INTERACTIVE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let dir = crate::test_util::test_dir("terminal_and_file_uncolored_when_not_interactive");
+
+            crate::console::set_interactive(false);
+            let log_config = LogConfig::terminal_and_file(Level(log::LevelFilter::Info), dir.join("app.log"), 64, 1);
+            crate::console::set_interactive(true);
+
+            let log_config = log_config.expect("Could not open");
+            assert_that(&log_config.color).is_false();
+            assert_that(&log_config.tee[0].color).is_false();
+        }
+
+        #[test]
+        fn mute_target_okay() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None);
+            let (_, logger) = dispatch(log_config).into_log();
+            let log_to = |target: &str| logger.log(&log::Record::builder()
+                .args(format_args!("{}", target))
+                .level(log::Level::Warn)
+                .target(target)
+                .build());
+
+            mute_target("clams::muted");
+            log_to("clams::muted");
+            log_to("clams::muted::inner");
+            log_to("clams::muted_not");
+            unmute_target("clams::muted");
+            log_to("clams::muted");
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec![
+                "WARN  clams::muted_not: clams::muted_not".to_owned(),
+                "WARN  clams::muted: clams::muted".to_owned(),
+            ]);
+        }
+
+        #[test]
+        fn level_names_round_trip() {
+            for (name, level) in Level::named() {
+                assert_that(&Level::from_str(name)).is_ok().is_equal_to(level.clone());
+                assert_that(&Level::from_str(&name.to_uppercase())).is_ok().is_equal_to(level);
+            }
+            assert_that(&Level::all().len()).is_equal_to(6);
+            assert_that(&Level::from_str("verbose")).is_err();
+        }
+
+        #[test]
+        fn verbosity_level_clamps() {
+            let level = |verbose, quiet| Verbosity { verbose, quiet }.level().name();
+
+            assert_that(&level(0, 3)).is_equal_to("off");
+            assert_that(&level(0, 2)).is_equal_to("off");
+            assert_that(&level(1, 2)).is_equal_to("error");
+            assert_that(&level(0, 0)).is_equal_to("warn");
+            assert_that(&level(2, 1)).is_equal_to("info");
+            assert_that(&level(2, 0)).is_equal_to("debug");
+            assert_that(&level(5, 0)).is_equal_to("trace");
+        }
+
+        #[cfg(feature = "structopt")]
+        #[test]
+        fn verbosity_from_args() {
+            use structopt::StructOpt;
+
+            let verbosity = Verbosity::from_iter(&["app", "-vvv", "--quiet"]);
+
+            assert_that(&verbosity).is_equal_to(Verbosity { verbose: 3, quiet: 1 });
+        }
+
+        #[test]
+        fn format_no_color_okay() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), Some("test".to_owned()));
+
+            log_warning(log_config);
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec!["[Context: test] WARN  clams::test: a message".to_owned()]);
+        }
+
+        #[test]
+        fn github_actions_okay() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, true, Level(log::LevelFilter::Info), Vec::new(), None)
+                .with_github_actions()
+                .with_location();
+            let (_, logger) = dispatch(log_config).into_log();
+            for (level, message) in &[(log::Level::Warn, "disk 95% full"), (log::Level::Error, "failed:\nretry"), (log::Level::Info, "moved")] {
+                logger.log(&log::Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(*level)
+                    .target("clams::test")
+                    .file(Some("src/a,b.rs"))
+                    .line(Some(42))
+                    .build());
+            }
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec![
+                "::warning file=src/a%2Cb.rs,line=42::clams::test: disk 95%25 full".to_owned(),
+                "::error file=src/a%2Cb.rs,line=42::clams::test: failed:%0Aretry".to_owned(),
+                "INFO  clams::test: moved".to_owned(),
+            ]);
+        }
+
+        #[test]
+        fn target_style_okay() {
+            let target = |style| Target { target: "my_app::subsystem::component::worker", style }.to_string();
+
+            assert_that(&target(TargetStyle::Full).as_str()).is_equal_to("my_app::subsystem::component::worker");
+            assert_that(&target(TargetStyle::Abbreviated).as_str()).is_equal_to("m::s::c::worker");
+            assert_that(&target(TargetStyle::LastSegments(2)).as_str()).is_equal_to("component::worker");
+            assert_that(&target(TargetStyle::LastSegments(9)).as_str()).is_equal_to("my_app::subsystem::component::worker");
+        }
+
+        #[test]
+        fn with_target_style_okay() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None)
+                .with_target_style(TargetStyle::Abbreviated);
+
+            log_warning(log_config);
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec!["WARN  c::test: a message".to_owned()]);
+        }
+
+        #[test]
+        fn with_format_okay() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, true, Level(log::LevelFilter::Info), Vec::new(), Some("test".to_owned()))
+                .with_format(|f, record, context| {
+                    write!(f, "{}|{}|{}", context.context().unwrap_or("-"), record.level(), record.args())
+                });
+
+            log_warning(log_config);
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec!["test|WARN|a message".to_owned()]);
+        }
+
+        #[test]
+        fn with_clock_okay() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None)
+                .with_clock(|| ::std::time::UNIX_EPOCH + ::std::time::Duration::from_secs(1_700_000_000))
+                .with_format(|f, record, context| {
+                    let secs = context.timestamp().duration_since(::std::time::UNIX_EPOCH).unwrap().as_secs();
+                    write!(f, "{} {} {}", secs, record.level(), record.args())
+                });
+
+            log_warning(log_config);
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec!["1700000000 WARN a message".to_owned()]);
+        }
+
+        #[test]
+        fn max_message_len_okay() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None)
+                .with_max_message_len(4);
+
+            log_warning(log_config);
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec!["WARN  clams::test: a me… [5 bytes truncated]".to_owned()]);
+        }
+
+        #[test]
+        fn with_location_okay() {
+            let (out, lines) = capture();
+            let log_config = LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None)
+                .with_location();
+
+            log_warning(log_config);
+
+            assert_that(&*lines.lock().unwrap()).is_equal_to(vec!["WARN  clams::test: a message (src/test.rs:42)".to_owned()]);
+        }
+
+        #[test]
+        fn truncate_is_char_boundary_safe() {
+            assert_that(&truncate("äöü".to_owned(), 3)).is_equal_to("ä… [4 bytes truncated]".to_owned());
+            assert_that(&truncate("äöü".to_owned(), 6)).is_equal_to("äöü".to_owned());
+        }
+
+        #[test]
+        fn ring_buffer_output_okay() {
+            let (out, lines) = capture();
+            let (ring, handle) = ring_buffer_output(2);
+            let log_config = LogConfig::new(Dispatch::new().chain(out).chain(ring), false, Level(log::LevelFilter::Info), Vec::new(), None);
+
+            log_warnings(log_config, &["one", "two", "three"]);
+
+            assert_that(&lines.lock().unwrap().len()).is_equal_to(3);
+            assert_that(&handle.lines()).is_equal_to(vec![
+                "WARN  clams::test: two".to_owned(),
+                "WARN  clams::test: three".to_owned(),
+            ]);
+            handle.clear();
+            assert_that(&handle.lines()).is_empty();
+        }
+
+        #[test]
+        fn file_output_append_and_truncate() {
+            let dir = crate::test_util::test_dir("file_output_append_and_truncate");
+            let path = dir.join("app.log");
+            ::std::fs::write(&path, "yesterday\n").expect("Could not write log");
+
+            let out = file_output(&path, true).expect("Could not open log");
+            log_warning(LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None));
+            let appended = ::std::fs::read_to_string(&path).expect("Could not read log");
+
+            let out = file_output(&path, false).expect("Could not open log");
+            log_warning(LogConfig::new(out, false, Level(log::LevelFilter::Info), Vec::new(), None));
+            let truncated = ::std::fs::read_to_string(&path).expect("Could not read log");
+
+            assert_that(&appended).is_equal_to("yesterday\nWARN  clams::test: a message\n".to_owned());
+            assert_that(&truncated).is_equal_to("WARN  clams::test: a message\n".to_owned());
+        }
+
+        #[test]
+        fn panic_message_okay() {
+            let static_str: Box<dyn Any + Send> = Box::new("static");
+            let string: Box<dyn Any + Send> = Box::new("owned".to_owned());
+            let other: Box<dyn Any + Send> = Box::new(42);
+
+            assert_that(&panic_message(&*static_str)).is_equal_to("static");
+            assert_that(&panic_message(&*string)).is_equal_to("owned");
+            assert_that(&panic_message(&*other)).is_equal_to("Box<dyn Any>");
+        }
+    }
+}
+
+pub mod progress {
+    use crate::theme;
+
+    use error_chain::*;
+    use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const FULL_BAR_RESERVED: usize = 50;
+    const COMPACT_BAR_RESERVED: usize = 30;
+    const COMPACT_BELOW: usize = 70;
+    const MIN_BAR_WIDTH: usize = 10;
+    const MAX_BAR_WIDTH: usize = 40;
+
+    /// Creates a bar of length `len` that is hidden unless `console::is_interactive()`.
+    pub fn new_bar(len: u64) -> ProgressBar {
+        let bar = ProgressBar::new(len);
+        bar.set_draw_target(draw_target());
+        bar
+    }
+
+    /// Creates a spinner that is hidden unless `console::is_interactive()`.
+    pub fn new_spinner() -> ProgressBar {
+        let spinner = ProgressBar::new_spinner();
+        spinner.set_draw_target(draw_target());
+        spinner
+    }
+
+    fn draw_target() -> ProgressDrawTarget {
+        if crate::console::is_interactive() {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::hidden()
+        }
+    }
+
+    /// Styles only decorate a bar; create bars with `new_bar` or `new_spinner` to honour batch mode.
+    pub trait ProgressStyleExt {
+        fn default_clams_spinner() -> ProgressStyle;
+
+        fn default_clams_bar() -> ProgressStyle;
+
+        fn default_clams_bar_compact() -> ProgressStyle {
+            let color = theme::style_name(theme::current().progress);
+            ProgressStyle::default_bar()
+                .template(&format!("[{{bar:20.{0}/{0}}}] {{pos}}/{{len}} {{wide_msg}}", color))
+        }
+
+        fn default_clams_bar_for_width(width: usize) -> ProgressStyle {
+            let color = theme::style_name(theme::current().progress);
+            if width < COMPACT_BELOW {
+                let template = format!("[{{bar:{}.{1}/{1}}}] {{pos}}/{{len}} {{wide_msg}}", bar_width(width, COMPACT_BAR_RESERVED), color);
+                ProgressStyle::default_bar().template(&template)
+            } else {
+                let template = format!(
+                    "[{{elapsed_precise}}] [{{bar:{}.{1}/{1}}}] {{pos}}/{{len}} ({{eta}}) {{wide_msg}} {{spinner:.{1}}}",
+                    bar_width(width, FULL_BAR_RESERVED), color
+                );
+                ProgressStyle::default_bar().template(&template)
+            }
+        }
+
+        fn default_clams_bar_adaptive() -> ProgressStyle {
+            terminal_width()
+                .map(Self::default_clams_bar_for_width)
+                .unwrap_or_else(Self::default_clams_bar)
+        }
+    }
+
+    impl ProgressStyleExt for ProgressStyle {
+        fn default_clams_spinner() -> ProgressStyle {
+            ProgressStyle::default_spinner()
+                .template("{prefix:.bold.dim} [{elapsed}] {spinner} {wide_msg}")
+        }
+
+        fn default_clams_bar() -> ProgressStyle {
+            let color = theme::style_name(theme::current().progress);
+            ProgressStyle::default_bar()
+                .template(&format!("[{{elapsed_precise}}] [{{bar:20.{0}/{0}}}] {{pos}}/{{len}} ({{eta}}) {{wide_msg}} {{spinner:.{0}}}", color))
+        }
+    }
+
+    pub trait Progress {
+        fn set_length(&self, len: u64);
+
+        fn set_position(&self, pos: u64);
+
+        fn inc(&self, delta: u64);
+
+        fn set_message(&self, msg: &str);
+
+        fn finish(&self);
+    }
+
+    impl Progress for ProgressBar {
+        fn set_length(&self, len: u64) {
+            ProgressBar::set_length(self, len)
+        }
+
+        fn set_position(&self, pos: u64) {
+            ProgressBar::set_position(self, pos)
+        }
+
+        fn inc(&self, delta: u64) {
+            ProgressBar::inc(self, delta)
+        }
+
+        fn set_message(&self, msg: &str) {
+            ProgressBar::set_message(self, msg)
+        }
+
+        fn finish(&self) {
+            ProgressBar::finish(self)
+        }
+    }
+
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub enum ProgressCall {
+        SetLength(u64),
+        SetPosition(u64),
+        Inc(u64),
+        SetMessage(String),
+        Finish,
+    }
+
+    /// Records every call instead of drawing, so progress-driving code can be asserted on without a terminal.
+    #[derive(Debug, Default)]
+    pub struct RecordingProgress {
+        calls: Mutex<Vec<ProgressCall>>,
+    }
+
+    impl RecordingProgress {
+        pub fn new() -> Self {
+            RecordingProgress::default()
+        }
+
+        pub fn calls(&self) -> Vec<ProgressCall> {
+            self.calls.lock().unwrap_or_else(|e| e.into_inner()).clone()
+        }
+
+        /// Position resulting from all `set_position` and `inc` calls so far.
+        pub fn position(&self) -> u64 {
+            self.calls().iter().fold(0, |pos, call| match call {
+                ProgressCall::SetPosition(pos) => *pos,
+                ProgressCall::Inc(delta) => pos + delta,
+                _ => pos,
+            })
+        }
+
+        pub fn finish_count(&self) -> usize {
+            self.calls().iter().filter(|call| **call == ProgressCall::Finish).count()
+        }
+
+        fn record(&self, call: ProgressCall) {
+            self.calls.lock().unwrap_or_else(|e| e.into_inner()).push(call);
+        }
+    }
+
+    impl Progress for RecordingProgress {
+        fn set_length(&self, len: u64) {
+            self.record(ProgressCall::SetLength(len))
+        }
+
+        fn set_position(&self, pos: u64) {
+            self.record(ProgressCall::SetPosition(pos))
+        }
+
+        fn inc(&self, delta: u64) {
+            self.record(ProgressCall::Inc(delta))
+        }
+
+        fn set_message(&self, msg: &str) {
+            self.record(ProgressCall::SetMessage(msg.to_owned()))
+        }
+
+        fn finish(&self) {
+            self.record(ProgressCall::Finish)
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub enum ProgressEventKind {
+        Started,
+        Tick,
+        Finished,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct ProgressEvent {
+        pub kind: ProgressEventKind,
+        pub position: u64,
+        pub length: u64,
+        pub message: String,
+        pub elapsed: Duration,
+    }
+
+    struct EventState {
+        started: Option<Instant>,
+        last_tick: Option<Instant>,
+        position: u64,
+        length: u64,
+        message: String,
+    }
+
+    type EventCallback = dyn Fn(&ProgressEvent) + Send + Sync;
+
+    /// Reports progress as events instead of, or in addition to, drawing a bar. The first update emits
+    /// `Started`, further updates emit at most one `Tick` per interval, and `finish` always emits `Finished`.
+    pub struct EventProgress {
+        bar: Option<ProgressBar>,
+        callback: Box<EventCallback>,
+        interval: Duration,
+        state: Mutex<EventState>,
+    }
+
+    impl EventProgress {
+        pub fn new<F: Fn(&ProgressEvent) + Send + Sync + 'static>(interval: Duration, callback: F) -> Self {
+            EventProgress {
+                bar: None,
+                callback: Box::new(callback),
+                interval,
+                state: Mutex::new(EventState {
+                    started: None,
+                    last_tick: None,
+                    position: 0,
+                    length: 0,
+                    message: String::new(),
+                }),
+            }
+        }
+
+        /// Emits events as `debug` log records with target `clams::progress`.
+        pub fn logging(interval: Duration) -> Self {
+            EventProgress::new(interval, |event| {
+                log::debug!(target: "clams::progress", "{:?} {}/{} after {:.1}s {}",
+                    event.kind, event.position, event.length, event.elapsed.as_secs_f64(), event.message);
+            })
+        }
+
+        /// Also drives `bar`, which is hidden unless `console::is_interactive()`.
+        pub fn with_bar(mut self, bar: ProgressBar) -> Self {
+            if !crate::console::is_interactive() {
+                bar.set_draw_target(ProgressDrawTarget::hidden());
+            }
+            self.bar = Some(bar);
+            self
+        }
+
+        fn update<F: FnOnce(&mut EventState)>(&self, finished: bool, f: F) {
+            let event = {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                f(&mut state);
+                let now = Instant::now();
+                let kind = match state.started {
+                    None => {
+                        state.started = Some(now);
+                        state.last_tick = Some(now);
+                        ProgressEventKind::Started
+                    }
+                    Some(_) if finished => ProgressEventKind::Finished,
+                    Some(_) => match state.last_tick {
+                        Some(last_tick) if now.duration_since(last_tick) < self.interval => return,
+                        _ => {
+                            state.last_tick = Some(now);
+                            ProgressEventKind::Tick
+                        }
+                    },
+                };
+                ProgressEvent {
+                    kind,
+                    position: state.position,
+                    length: state.length,
+                    message: state.message.clone(),
+                    elapsed: state.started.map(|started| now.duration_since(started)).unwrap_or_default(),
+                }
+            };
+            (self.callback)(&event);
+        }
+    }
+
+    impl Progress for EventProgress {
+        fn set_length(&self, len: u64) {
+            if let Some(ref bar) = self.bar {
+                bar.set_length(len);
+            }
+            self.update(false, |state| state.length = len);
+        }
+
+        fn set_position(&self, pos: u64) {
+            if let Some(ref bar) = self.bar {
+                bar.set_position(pos);
+            }
+            self.update(false, |state| state.position = pos);
+        }
+
+        fn inc(&self, delta: u64) {
+            if let Some(ref bar) = self.bar {
+                bar.inc(delta);
+            }
+            self.update(false, |state| state.position += delta);
+        }
+
+        fn set_message(&self, msg: &str) {
+            if let Some(ref bar) = self.bar {
+                bar.set_message(msg);
+            }
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.message = msg.to_owned();
+        }
+
+        fn finish(&self) {
+            if let Some(ref bar) = self.bar {
+                bar.finish();
+            }
+            self.update(true, |_| {});
+        }
+    }
+
+    /// Forwards to `inner` and logs byte throughput at `info` with target `clams::progress` at most once per `interval`,
+    /// e.g. "copied 4.20GB / 10.00GB (42%, 85.00MB/s)"; meant for headless jobs wrapping a `new_bar`, which is hidden in batch mode.
+    pub struct LogThrottle<P: Progress> {
+        inner: P,
+        events: EventProgress,
+    }
+
+    impl<P: Progress> LogThrottle<P> {
+        pub fn new(inner: P, interval: Duration) -> Self {
+            LogThrottle {
+                inner,
+                events: EventProgress::new(interval, |event| {
+                    log::info!(target: "clams::progress", "{}", throughput_line(event));
+                }),
+            }
+        }
+    }
+
+    impl<P: Progress> Progress for LogThrottle<P> {
+        fn set_length(&self, len: u64) {
+            self.inner.set_length(len);
+            self.events.set_length(len);
+        }
+
+        fn set_position(&self, pos: u64) {
+            self.inner.set_position(pos);
+            self.events.set_position(pos);
+        }
+
+        fn inc(&self, delta: u64) {
+            self.inner.inc(delta);
+            self.events.inc(delta);
+        }
+
+        fn set_message(&self, msg: &str) {
+            self.inner.set_message(msg);
+            self.events.set_message(msg);
+        }
+
+        fn finish(&self) {
+            self.inner.finish();
+            self.events.finish();
+        }
+    }
+
+    fn throughput_line(event: &ProgressEvent) -> String {
+        let secs = event.elapsed.as_secs_f64();
+        let rate = if secs > 0.0 { (event.position as f64 / secs) as u64 } else { 0 };
+        let prefix = if event.message.is_empty() { String::new() } else { format!("{} ", event.message) };
+        if let Some(percent) = (event.position.min(event.length) * 100).checked_div(event.length) {
+            format!("{}{} / {} ({}%, {}/s)", prefix, HumanBytes(event.position), HumanBytes(event.length), percent, HumanBytes(rate))
+        } else {
+            format!("{}{} ({}/s)", prefix, HumanBytes(event.position), HumanBytes(rate))
+        }
+    }
+
+    /// Runs `f` with a handle for adding clams-styled bars to a `MultiProgress` drawn by a background thread.
+    ///
+    /// When `f` returns or panics, all bars still running are finished, the display is cleared and the draw
+    /// thread is joined, so unfinished bars cannot block the program.
+    pub fn scoped_multi<F, R>(f: F) -> R where F: FnOnce(&MultiProgressHandle) -> R {
+        let multi = MultiProgress::new();
+        if !crate::console::is_interactive() {
+            multi.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        // Keeps the draw thread alive until teardown even if `f` has not added any bar yet.
+        let sentinel = Arc::new(multi.add(ProgressBar::new(1)));
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _ = multi.join_and_clear();
+            });
+            let handle = MultiProgressHandle { multi: &multi, bars: Mutex::new(vec![sentinel]) };
+            f(&handle)
+        })
+    }
+
+    pub struct MultiProgressHandle<'a> {
+        multi: &'a MultiProgress,
+        bars: Mutex<Vec<Arc<ProgressBar>>>,
+    }
+
+    impl MultiProgressHandle<'_> {
+        pub fn add_bar(&self, len: u64) -> Arc<ProgressBar> {
+            self.add(new_bar(len), ProgressStyle::default_clams_bar())
+        }
+
+        pub fn add_spinner(&self) -> Arc<ProgressBar> {
+            self.add(new_spinner(), ProgressStyle::default_clams_spinner())
+        }
+
+        fn add(&self, bar: ProgressBar, style: ProgressStyle) -> Arc<ProgressBar> {
+            bar.set_style(style);
+            let bar = Arc::new(self.multi.add(bar));
+            self.bars.lock().unwrap_or_else(|e| e.into_inner()).push(bar.clone());
+            bar
+        }
+    }
+
+    impl Drop for MultiProgressHandle<'_> {
+        fn drop(&mut self) {
+            for bar in self.bars.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+                bar.finish_and_clear();
+            }
+        }
+    }
+
+    /// Building blocks of a progress template; see `TemplateBuilder`.
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub enum Segment {
+        Prefix,
+        Elapsed,
+        ElapsedPrecise,
+        /// Bar with a fixed width in characters.
+        Bar(usize),
+        /// Bar taking up the remaining line width.
+        WideBar,
+        Pos,
+        Len,
+        Percent,
+        Bytes,
+        TotalBytes,
+        Eta,
+        EtaPrecise,
+        Msg,
+        /// Message taking up the remaining line width.
+        WideMsg,
+        Spinner,
+        /// Fixed text, e.g. brackets or a separator; must not contain `{` or `}`.
+        Literal(String),
+    }
+
+    impl Segment {
+        fn placeholder(&self) -> String {
+            let key = match *self {
+                Segment::Prefix => "prefix",
+                Segment::Elapsed => "elapsed",
+                Segment::ElapsedPrecise => "elapsed_precise",
+                Segment::Bar(width) => return format!("{{bar:{}}}", width),
+                Segment::WideBar => "wide_bar",
+                Segment::Pos => "pos",
+                Segment::Len => "len",
+                Segment::Percent => "percent",
+                Segment::Bytes => "bytes",
+                Segment::TotalBytes => "total_bytes",
+                Segment::Eta => "eta",
+                Segment::EtaPrecise => "eta_precise",
+                Segment::Msg => "msg",
+                Segment::WideMsg => "wide_msg",
+                Segment::Spinner => "spinner",
+                Segment::Literal(ref text) => return text.to_owned(),
+            };
+            format!("{{{}}}", key)
+        }
+
+        fn is_wide(&self) -> bool {
+            matches!(*self, Segment::WideBar | Segment::WideMsg)
+        }
+    }
+
+    /// Assembles a progress template from `Segment`s separated by a space and validates it before indicatif sees it.
+    #[derive(Clone, Debug, Default)]
+    pub struct TemplateBuilder {
+        segments: Vec<Segment>,
+    }
+
+    impl TemplateBuilder {
+        pub fn new() -> Self {
+            TemplateBuilder::default()
+        }
+
+        pub fn segment(mut self, segment: Segment) -> Self {
+            self.segments.push(segment);
+            self
+        }
+
+        pub fn template(&self) -> Result<String> {
+            if self.segments.is_empty() {
+                return Err(ErrorKind::InvalidTemplate("no segments".to_owned()).into());
+            }
+            if self.segments.iter().filter(|segment| segment.is_wide()).count() > 1 {
+                return Err(ErrorKind::InvalidTemplate("at most one wide segment is allowed".to_owned()).into());
+            }
+            for segment in &self.segments {
+                match *segment {
+                    Segment::Bar(0) => return Err(ErrorKind::InvalidTemplate("bar width must be positive".to_owned()).into()),
+                    Segment::Literal(ref text) if text.contains(&['{', '}', '\n'][..]) =>
+                        return Err(ErrorKind::InvalidTemplate(format!("literal '{}' must not contain braces or line breaks", text)).into()),
+                    _ => {}
+                }
+            }
+
+            Ok(self.segments.iter().map(Segment::placeholder).collect::<Vec<_>>().join(" "))
+        }
+
+        pub fn build(&self) -> Result<ProgressStyle> {
+            let template = self.template()?;
+
+            Ok(ProgressStyle::default_bar().template(&template))
+        }
+    }
+
+    /// Columns of the terminal attached to stderr, where bars are drawn, or `None` if stderr is not a terminal.
+    #[cfg(unix)]
+    pub fn terminal_width() -> Option<usize> {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let res = unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) };
+        if res == 0 && size.ws_col > 0 { Some(size.ws_col as usize) } else { None }
+    }
+
+    #[cfg(not(unix))]
+    pub fn terminal_width() -> Option<usize> {
+        None
+    }
+
+    fn bar_width(width: usize, reserved: usize) -> usize {
+        width.saturating_sub(reserved).clamp(MIN_BAR_WIDTH, MAX_BAR_WIDTH)
+    }
+
+    error_chain! {
+        errors {
+            InvalidTemplate(reason: String) {
+                description("Invalid progress template")
+                display("Invalid progress template: {}", reason)
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::test_util::INTERACTIVE_LOCK;
+
+        use spectral::prelude::*;
+
+        use std::sync::Arc;
+
+        fn recording(interval: Duration) -> (EventProgress, Arc<Mutex<Vec<ProgressEvent>>>) {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let sink = events.clone();
+            let progress = EventProgress::new(interval, move |event| sink.lock().unwrap().push(event.clone()));
+            (progress, events)
+        }
+
+        fn kinds(events: &Arc<Mutex<Vec<ProgressEvent>>>) -> Vec<ProgressEventKind> {
+            events.lock().unwrap().iter().map(|e| e.kind).collect()
+        }
+
+        #[test]
+        fn event_progress_emits_every_tick_without_interval() {
+            let (progress, events) = recording(Duration::from_secs(0));
+
+            progress.set_length(3);
+            progress.inc(1);
+            progress.inc(2);
+            progress.finish();
+
+            assert_that(&kinds(&events)).is_equal_to(vec![
+                ProgressEventKind::Started, ProgressEventKind::Tick, ProgressEventKind::Tick, ProgressEventKind::Finished,
+            ]);
+            let last = events.lock().unwrap().last().cloned().unwrap();
+            assert_that(&(last.position, last.length)).is_equal_to((3, 3));
+        }
+
+        #[test]
+        fn event_progress_throttles_ticks() {
+            let (progress, events) = recording(Duration::from_secs(3600));
+
+            progress.set_length(100);
+            for _ in 0..100 {
+                progress.inc(1);
+            }
+            progress.finish();
+
+            assert_that(&kinds(&events)).is_equal_to(vec![ProgressEventKind::Started, ProgressEventKind::Finished]);
+        }
+
+        #[test]
+        fn recording_progress_is_shareable_across_threads() {
+            let progress = RecordingProgress::new();
+            progress.set_length(8);
+
+            thread::scope(|scope| {
+                for _ in 0..8 {
+                    scope.spawn(|| progress.inc(1));
+                }
+            });
+            progress.set_message("done");
+            progress.finish();
+
+            assert_that(&progress.position()).is_equal_to(8);
+            assert_that(&progress.finish_count()).is_equal_to(1);
+            assert_that(&progress.calls().len()).is_equal_to(11);
+            assert_that(&progress.calls().last()).is_equal_to(Some(&ProgressCall::Finish));
+        }
+
+        #[test]
+        fn throughput_line_okay() {
+            let event = ProgressEvent {
+                kind: ProgressEventKind::Tick,
+                position: 42 * 1024 * 1024,
+                length: 100 * 1024 * 1024,
+                message: "copied".to_owned(),
+                elapsed: Duration::from_secs(2),
+            };
+
+            assert_that(&throughput_line(&event).as_str()).is_equal_to("copied 42.00MB / 100.00MB (42%, 21.00MB/s)");
+        }
+
+        #[test]
+        fn throughput_line_unknown_length() {
+            let event = ProgressEvent {
+                kind: ProgressEventKind::Tick,
+                position: 2048,
+                length: 0,
+                message: String::new(),
+                elapsed: Duration::from_secs(0),
+            };
+
+            assert_that(&throughput_line(&event).as_str()).is_equal_to("2.00KB (0B/s)");
+        }
+
+        #[test]
+        fn scoped_multi_returns_result() {
+            let res = scoped_multi(|multi| {
+                let bar = multi.add_bar(10);
+                bar.inc(10);
+                bar.finish();
+                let _unfinished = multi.add_spinner();
+                42
+            });
+
+            assert_that(&res).is_equal_to(42);
+        }
+
+        #[test]
+        fn scoped_multi_tears_down_on_panic() {
+            let res = std::panic::catch_unwind(|| {
+                scoped_multi(|multi| {
+                    let _bar = multi.add_bar(10);
+                    panic!("work failed");
+                })
+            });
+
+            assert_that(&res.is_err()).is_true();
+        }
+
+        #[test]
+        fn batch_mode_hides_bars() {
+            let _lock = INTERACTIVE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            crate::console::set_interactive(false);
+            let hidden = draw_target().is_hidden();
+            let res = scoped_multi(|multi| {
+                let _unfinished = multi.add_spinner();
+                42
+            });
+            crate::console::set_interactive(true);
+
+            assert_that(&hidden).is_true();
+            assert_that(&res).is_equal_to(42);
+        }
+
+        #[test]
+        fn template_builder_okay() {
+            let builder = TemplateBuilder::new()
+                .segment(Segment::Literal("[".to_owned()))
+                .segment(Segment::Elapsed)
+                .segment(Segment::Literal("]".to_owned()))
+                .segment(Segment::Bar(20))
+                .segment(Segment::Bytes)
+                .segment(Segment::Literal("/".to_owned()))
+                .segment(Segment::TotalBytes)
+                .segment(Segment::WideMsg);
+
+            assert_that(&builder.template()).is_ok()
+                .is_equal_to("[ {elapsed} ] {bar:20} {bytes} / {total_bytes} {wide_msg}".to_owned());
+            assert_that(&builder.build().is_ok()).is_true();
+        }
+
+        #[test]
+        fn template_builder_invalid() {
+            assert_that(&TemplateBuilder::new().template()).is_err();
+            assert_that(&TemplateBuilder::new().segment(Segment::WideBar).segment(Segment::WideMsg).template()).is_err();
+            assert_that(&TemplateBuilder::new().segment(Segment::Bar(0)).template()).is_err();
+            assert_that(&TemplateBuilder::new().segment(Segment::Literal("{pos}".to_owned())).template()).is_err();
+        }
+
+        #[test]
+        fn bar_width_is_clamped() {
+            assert_that(&bar_width(40, COMPACT_BAR_RESERVED)).is_equal_to(MIN_BAR_WIDTH);
+            assert_that(&bar_width(80, FULL_BAR_RESERVED)).is_equal_to(30);
+            assert_that(&bar_width(200, FULL_BAR_RESERVED)).is_equal_to(MAX_BAR_WIDTH);
+        }
+    }
+}
+
+pub mod theme {
+    use colored::Color;
+    use std::sync::RwLock;
+
+    /// One palette for log level colors, progress bars and the console's `success`, `warning` and `failure`.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub struct Theme {
+        pub error: Color,
+        pub warn: Color,
+        pub info: Color,
+        pub debug: Color,
+        pub trace: Color,
+        pub progress: Color,
+        pub success: Color,
+        pub warning: Color,
+        pub failure: Color,
+    }
+
+    const DEFAULT_THEME: Theme = Theme {
+        error: Color::Red,
+        warn: Color::Yellow,
+        info: Color::Green,
+        debug: Color::Blue,
+        trace: Color::White,
+        progress: Color::Blue,
+        success: Color::Green,
+        warning: Color::Yellow,
+        failure: Color::Red,
+    };
+
+    impl Default for Theme {
+        fn default() -> Self {
+            DEFAULT_THEME
+        }
+    }
+
+    static CURRENT: RwLock<Theme> = RwLock::new(DEFAULT_THEME);
+
+    /// Installs `theme` for all subsystems; affects loggers initialized and progress styles created afterwards.
+    pub fn apply(theme: &Theme) {
+        *CURRENT.write().unwrap_or_else(|e| e.into_inner()) = *theme;
+    }
+
+    pub fn current() -> Theme {
+        *CURRENT.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Name of `color` in indicatif templates.
+    pub(crate) fn style_name(color: Color) -> &'static str {
+        match color {
+            Color::Black => "black",
+            Color::Red => "red",
+            Color::Green => "green",
+            Color::Yellow => "yellow",
+            Color::Blue => "blue",
+            Color::Magenta => "magenta",
+            Color::Cyan => "cyan",
+            Color::White => "white",
+            Color::BrightBlack => "black.bright",
+            Color::BrightRed => "red.bright",
+            Color::BrightGreen => "green.bright",
+            Color::BrightYellow => "yellow.bright",
+            Color::BrightBlue => "blue.bright",
+            Color::BrightMagenta => "magenta.bright",
+            Color::BrightCyan => "cyan.bright",
+            Color::BrightWhite => "white.bright",
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        use spectral::prelude::*;
+
+        #[test]
+        fn default_theme_is_current() {
+            assert_that(&current()).is_equal_to(Theme::default());
+            assert_that(&style_name(Color::BrightCyan)).is_equal_to("cyan.bright");
+        }
+    }
+}